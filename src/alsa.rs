@@ -0,0 +1,125 @@
+//! # ALSA for CarPlay Client
+//!
+//! The handful of libasound calls it takes to play and record interleaved
+//! 16 bit audio. Only built with the `alsa` feature, which links the system
+//! library.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+use std::ptr;
+use std::time::Duration;
+
+const SND_PCM_STREAM_PLAYBACK: c_int = 0;
+const SND_PCM_STREAM_CAPTURE: c_int = 1;
+const SND_PCM_FORMAT_S16_LE: c_int = 2;
+const SND_PCM_ACCESS_RW_INTERLEAVED: c_int = 3;
+
+#[link(name = "asound")]
+extern "C" {
+    fn snd_pcm_open(pcm: *mut *mut c_void, name: *const c_char, stream: c_int, mode: c_int) -> c_int;
+    fn snd_pcm_set_params(pcm: *mut c_void, format: c_int, access: c_int, channels: c_uint,
+                          rate: c_uint, soft_resample: c_int, latency: c_uint) -> c_int;
+    fn snd_pcm_writei(pcm: *mut c_void, buffer: *const c_void, frames: c_ulong) -> c_long;
+    fn snd_pcm_readi(pcm: *mut c_void, buffer: *mut c_void, frames: c_ulong) -> c_long;
+    fn snd_pcm_recover(pcm: *mut c_void, err: c_int, silent: c_int) -> c_int;
+    fn snd_pcm_drain(pcm: *mut c_void) -> c_int;
+    fn snd_pcm_drop(pcm: *mut c_void) -> c_int;
+    fn snd_pcm_close(pcm: *mut c_void) -> c_int;
+    fn snd_strerror(errnum: c_int) -> *const c_char;
+}
+
+fn error(what: &str, code: c_int) -> io::Error {
+    let message = unsafe { CStr::from_ptr(snd_strerror(code)) }.to_string_lossy();
+    io::Error::other(format!("ALSA {}: {}", what, message))
+}
+
+/// A PCM device opened for playback or for capture.
+pub struct Pcm {
+    pcm: *mut c_void,
+    channels: usize,
+    capture: bool,
+}
+
+impl Pcm {
+    /// `device` is an ALSA device name such as `default` or `hw:0,0`.
+    /// ALSA converts the rate itself if the hardware can't do it.
+    pub fn open_playback(device: &str, rate: u32, channels: u16, latency: Duration) -> io::Result<Self> {
+        Self::open(device, SND_PCM_STREAM_PLAYBACK, rate, channels, latency)
+    }
+
+    pub fn open_capture(device: &str, rate: u32, channels: u16, latency: Duration) -> io::Result<Self> {
+        Self::open(device, SND_PCM_STREAM_CAPTURE, rate, channels, latency)
+    }
+
+    fn open(device: &str, stream: c_int, rate: u32, channels: u16, latency: Duration) -> io::Result<Self> {
+        let name = CString::new(device).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut pcm = ptr::null_mut();
+        let result = unsafe { snd_pcm_open(&mut pcm, name.as_ptr(), stream, 0) };
+        if result < 0 {
+            return Err(error(&format!("could not open {}", device), result));
+        }
+        let pcm = Self { pcm, channels: channels as usize, capture: stream == SND_PCM_STREAM_CAPTURE };
+        let result = unsafe {
+            snd_pcm_set_params(pcm.pcm, SND_PCM_FORMAT_S16_LE, SND_PCM_ACCESS_RW_INTERLEAVED,
+                               channels as c_uint, rate, 1, latency.as_micros() as c_uint)
+        };
+        if result < 0 {
+            return Err(error("could not set the format", result));
+        }
+        Ok(pcm)
+    }
+
+    /// Blocks until all of `samples` is queued. Underruns are recovered
+    /// from and played through.
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut rest = samples;
+        while !rest.is_empty() {
+            let frames = rest.len() / self.channels;
+            let written = unsafe { snd_pcm_writei(self.pcm, rest.as_ptr() as *const c_void, frames as c_ulong) };
+            if written < 0 {
+                let result = unsafe { snd_pcm_recover(self.pcm, written as c_int, 1) };
+                if result < 0 {
+                    return Err(error("write failed", result));
+                }
+                continue;
+            }
+            rest = &rest[written as usize * self.channels..];
+        }
+        Ok(())
+    }
+
+    /// Blocks until `samples` is full. Overruns are recovered from; the
+    /// audio that was lost is simply missing.
+    pub fn read(&mut self, samples: &mut [i16]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < samples.len() {
+            let rest = &mut samples[filled..];
+            let frames = rest.len() / self.channels;
+            let read = unsafe { snd_pcm_readi(self.pcm, rest.as_mut_ptr() as *mut c_void, frames as c_ulong) };
+            if read < 0 {
+                let result = unsafe { snd_pcm_recover(self.pcm, read as c_int, 1) };
+                if result < 0 {
+                    return Err(error("read failed", result));
+                }
+                continue;
+            }
+            filled += read as usize * self.channels;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pcm {
+    fn drop(&mut self) {
+        unsafe {
+            // Let playback finish, but don't wait for more to be recorded
+            if self.capture {
+                snd_pcm_drop(self.pcm);
+            } else {
+                snd_pcm_drain(self.pcm);
+            }
+            snd_pcm_close(self.pcm);
+        }
+    }
+}
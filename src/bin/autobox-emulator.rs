@@ -0,0 +1,306 @@
+//! # AutoBox Emulator
+//!
+//! Plays the part of the AutoBox Server hardware over a TCP or Unix socket
+//! so the client can be developed without a dongle. It answers `OpenBox`
+//! like the real box does, reports a phone as plugged in, then loops an
+//! H.264 file as `Video` and a WAV file as `Audio` until the client goes
+//! away. Point the client at it with `--tcp` or `--unix`.
+
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use carplay_client::link_layer::box_protocol::*;
+use carplay_client::link_layer::buffer_pool::{BufferPool, Payload};
+use carplay_client::link_layer::framing;
+use carplay_client::link_layer::transport::{Transport, TransportError};
+use carplay_client::link_layer::transport::socket::SocketTransport;
+use carplay_client::h264::{self, FrameKind};
+use carplay_client::wav::{self, Wav};
+
+const USAGE: &str = "\
+Usage: autobox-emulator [OPTIONS]
+
+Options:
+  --tcp ADDR      Listen on ADDR (default 127.0.0.1:5555)
+  --unix PATH     Listen on the Unix socket PATH instead
+  --video FILE    Loop this H.264 elementary stream as Video messages
+  --audio FILE    Loop this 16 bit PCM WAV file as Audio messages
+  --fps N         Video rate (default: the rate the client asks for)
+  --help          Print this message and exit";
+
+const SW_VERSION: &str = "2021.01.25.0001";
+const BT_ADDR: &str = "00:11:22:33:44:55";
+const RX_TIMEOUT: Duration = Duration::from_secs(1);
+// Long enough that a slow client stalls us rather than truncating a frame
+const TX_TIMEOUT: Duration = Duration::from_secs(10);
+const AUDIO_PACKET: Duration = Duration::from_millis(20);
+
+enum Listen {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+struct Options {
+    listen: Listen,
+    video: Option<PathBuf>,
+    audio: Option<PathBuf>,
+    fps: Option<u32>,
+}
+
+struct Media {
+    /// One entry per access unit, start codes included.
+    video: Vec<Payload>,
+    /// Indices into `video` that decoding can start from.
+    idr_frames: Vec<usize>,
+    audio: Option<(u32, Wav)>,
+    fps: Option<u32>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        listen: Listen::Tcp("127.0.0.1:5555".to_string()),
+        video: None,
+        audio: None,
+        fps: None,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--tcp" => options.listen = Listen::Tcp(value()?),
+            "--unix" => options.listen = Listen::Unix(value()?.into()),
+            "--video" => options.video = Some(value()?.into()),
+            "--audio" => options.audio = Some(value()?.into()),
+            "--fps" => {
+                let fps = value()?;
+                options.fps = Some(fps.parse().ok().filter(|&fps| fps > 0)
+                                      .ok_or(format!("invalid frame rate `{}`", fps))?);
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("unknown option `{}`", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn load_media(options: &Options) -> Result<Media, String> {
+    let mut idr_frames = Vec::new();
+    let video = match options.video {
+        Some(ref path) => {
+            let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let units = h264::split_access_units(&data);
+            if units.is_empty() {
+                return Err(format!("{}: no H.264 access units found", path.display()));
+            }
+            println!("Loaded {} access units from {}", units.len(), path.display());
+            idr_frames = (0..units.len()).filter(|&index| h264::classify(&units[index]) == FrameKind::Idr)
+                                         .collect();
+            if idr_frames.is_empty() {
+                println!("{}: no IDR frame, keyframe requests will be ignored", path.display());
+            }
+            units.into_iter().map(Payload::from).collect()
+        }
+        None => Vec::new(),
+    };
+    let audio = match options.audio {
+        Some(ref path) => {
+            let wav = wav::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let decode_type = decode_type_for(wav.format.sample_rate, wav.format.channels)
+                .ok_or(format!("{}: the dongle has no decode type for {} Hz with {} channels",
+                               path.display(), wav.format.sample_rate, wav.format.channels))?;
+            Some((decode_type, wav))
+        }
+        None => None,
+    };
+    Ok(Media { video, idr_frames, audio, fps: options.fps })
+}
+
+fn send(transport: &dyn Transport, msg: MsgType) -> bool {
+    match framing::write_msg(transport, &msg, TX_TIMEOUT) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("Send failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Flags the connection thread uses to steer the streaming thread.
+struct Control {
+    running: AtomicBool,
+    keyframe_requested: AtomicBool,
+}
+
+/// Runs after `OpenBox`: announces the box and a phone, then streams media
+/// until `control.running` is cleared.
+fn stream(transport: &dyn Transport, media: &Media, open: (u32, u32, u32), control: &Control) {
+    let (width, height, framerate) = open;
+    let startup = vec![
+        MsgType::SwVer(SwVer::new(SW_VERSION)),
+        MsgType::BtAddr(BtAddr::new(BT_ADDR)),
+        MsgType::DevPlug(DevPlug::new(PHONE_TYPE_CARPLAY)),
+    ];
+    for msg in startup {
+        if !send(transport, msg) {
+            return;
+        }
+    }
+    if let Some((decode_type, _)) = media.audio {
+        for &command in &[AudioCommand::OutputStart, AudioCommand::MediaStart] {
+            if !send(transport, MsgType::Audio(Audio::with_command(decode_type, AUDIO_TYPE_MEDIA, command))) {
+                return;
+            }
+        }
+    }
+
+    let fps = media.fps.unwrap_or(framerate).max(1);
+    let frame_interval = Duration::from_secs(1) / fps;
+    let mut next_video = Instant::now();
+    let mut next_audio = Instant::now();
+    let mut video_index = 0;
+    let mut audio_offset = 0;
+    while control.running.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if control.keyframe_requested.swap(false, Ordering::Relaxed) {
+            // Like the real box, answer with the next IDR rather than a new one
+            let next_idr = media.idr_frames.iter().find(|&&index| index >= video_index)
+                                .or_else(|| media.idr_frames.first());
+            if let Some(&index) = next_idr {
+                video_index = index;
+            }
+        }
+        if !media.video.is_empty() && (now >= next_video) {
+            let unit = media.video[video_index].clone();
+            if !send(transport, MsgType::Video(Video::new(width, height, unit))) {
+                return;
+            }
+            video_index = (video_index + 1) % media.video.len();
+            next_video += frame_interval;
+        }
+        if let Some((decode_type, ref wav)) = media.audio {
+            if now >= next_audio {
+                let channels = wav.format.channels as usize;
+                let len = (wav.format.sample_rate as usize * AUDIO_PACKET.as_millis() as usize / 1000) * channels;
+                let chunk: Vec<i16> = wav.samples.iter().cycle().skip(audio_offset).take(len).copied().collect();
+                audio_offset = (audio_offset + len) % wav.samples.len().max(1);
+                if !send(transport, MsgType::Audio(Audio::new(decode_type, AUDIO_TYPE_MEDIA, wav::to_bytes(&chunk)))) {
+                    return;
+                }
+                next_audio += AUDIO_PACKET;
+            }
+        }
+        let mut wake = now + Duration::from_millis(100);
+        if !media.video.is_empty() {
+            wake = wake.min(next_video);
+        }
+        if media.audio.is_some() {
+            wake = wake.min(next_audio);
+        }
+        thread::sleep(wake.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Talks to one connected client until it disconnects.
+fn serve(transport: Arc<dyn Transport>, media: &Arc<Media>) {
+    println!("Client connected");
+    let control = Arc::new(Control {
+        running: AtomicBool::new(true),
+        keyframe_requested: AtomicBool::new(false),
+    });
+    let pool = BufferPool::default();
+    let mut streamer: Option<thread::JoinHandle<()>> = None;
+    loop {
+        match framing::read_msg(&*transport, &pool, RX_TIMEOUT) {
+            Ok(Some(MsgType::OpenBox(open))) => {
+                println!("OpenBox {}x{} @ {} fps", open.width(), open.height(), open.framerate());
+                if streamer.is_none() {
+                    let (transport, media, control) = (transport.clone(), media.clone(), control.clone());
+                    let open = (open.width(), open.height(), open.framerate());
+                    streamer = Some(thread::spawn(move || stream(&*transport, &media, open, &control)));
+                }
+            }
+            Ok(Some(MsgType::Touch(touch))) => {
+                let (x, y) = touch.position();
+                println!("Touch action {} at ({}, {})", touch.action(), x, y);
+            }
+            Ok(Some(MsgType::ButtonCtl(button))) => {
+                println!("ButtonCtl {}", button.command());
+                if button.command() == BUTTON_REQUEST_KEYFRAME {
+                    control.keyframe_requested.store(true, Ordering::Relaxed);
+                }
+            }
+            Ok(Some(MsgType::CloseBox(_))) => {
+                println!("Client closed the box");
+                break;
+            }
+            Ok(Some(MsgType::Heartbeat(_))) => {},
+            Ok(Some(msg)) => println!("Ignoring message type {}", msg.msg_type()),
+            Ok(None) | Err(TransportError::Timeout) => {},
+            Err(e) => {
+                println!("Client went away: {}", e);
+                break;
+            }
+        }
+    }
+    control.running.store(false, Ordering::Relaxed);
+    if let Some(streamer) = streamer {
+        let _ = streamer.join();
+    }
+}
+
+fn listen(options: &Options, media: Arc<Media>) -> std::io::Result<()> {
+    match options.listen {
+        Listen::Tcp(ref addr) => {
+            let listener = TcpListener::bind(addr)?;
+            println!("Listening on {}", addr);
+            for stream in listener.incoming() {
+                let stream = stream?;
+                stream.set_nodelay(true)?;
+                serve(Arc::new(SocketTransport::new(stream)), &media);
+            }
+        }
+        Listen::Unix(ref path) => {
+            // A previous run may have left its socket behind
+            if Path::new(path).exists() {
+                fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            println!("Listening on {}", path.display());
+            for stream in listener.incoming() {
+                serve(Arc::new(SocketTransport::new(stream?)), &media);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("autobox-emulator: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let media = match load_media(&options) {
+        Ok(media) => Arc::new(media),
+        Err(e) => {
+            eprintln!("autobox-emulator: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = listen(&options, media) {
+        eprintln!("autobox-emulator: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,125 @@
+//! # Voice Processor
+//!
+//! Runs the client's microphone processing over WAV files, so echo
+//! cancellation and noise suppression can be tried and tuned on recorded
+//! fixtures. The reference is what the speakers played while the
+//! microphone recording was made, starting at the same moment.
+
+use std::path::{Path, PathBuf};
+use std::process;
+
+use carplay_client::input_layer::voice_processing::{VoiceConfig, VoiceProcessor};
+use carplay_client::player_layer::resampler::Resampler;
+use carplay_client::wav::{self, Wav, WavFormat, WavWriter};
+
+const USAGE: &str = "\
+Usage: voice-processor [OPTIONS] MIC OUT
+
+Processes the microphone recording MIC and writes the result to OUT, both
+16 bit PCM WAV files. OUT is mono, at the rate of MIC.
+
+Options:
+  --reference FILE  Cancel the echo of FILE, what was played meanwhile
+  --denoise         Suppress steady background noise
+  --help            Print this message and exit";
+
+/// Samples processed at a time, as the microphone hands them over.
+const BLOCK_MS: usize = 20;
+
+struct Options {
+    mic: PathBuf,
+    out: PathBuf,
+    reference: Option<PathBuf>,
+    denoise: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let (mut reference, mut denoise) = (None, false);
+    let mut files = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--reference" => reference = Some(PathBuf::from(value()?)),
+            "--denoise" => denoise = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+    match (files.pop(), files.pop(), files.is_empty()) {
+        (Some(out), Some(mic), true) => Ok(Options { mic, out, reference, denoise }),
+        _ => Err("expected MIC and OUT".to_string()),
+    }
+}
+
+fn read(path: &Path) -> Result<Wav, String> {
+    wav::read(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Mono, at `sample_rate`, full scale at 1.0.
+fn to_mono(file: &Wav, sample_rate: u32) -> Vec<f32> {
+    let mut samples = Vec::new();
+    Resampler::new(file.format.sample_rate, file.format.channels, sample_rate, 1)
+        .process(&file.samples, &mut samples);
+    samples.into_iter().map(|sample| sample as f32 / 32768.0).collect()
+}
+
+fn level(samples: &[f32]) -> String {
+    let power = samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32;
+    format!("{:.1} dBFS", 10.0 * power.max(1e-12).log10())
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let file = read(&options.mic)?;
+    let sample_rate = file.format.sample_rate;
+    let mic = to_mono(&file, sample_rate);
+    let mut reference = match options.reference {
+        Some(ref path) => to_mono(&read(path)?, sample_rate),
+        None => Vec::new(),
+    };
+    let config = VoiceConfig {
+        echo_cancellation: options.reference.is_some(),
+        noise_suppression: options.denoise,
+        reference: None,
+    };
+    let mut processor = VoiceProcessor::new(&config, sample_rate);
+    let delay = processor.delay();
+    // Run on past the end to get the delayed tail out too
+    let mut processed = mic.clone();
+    processed.resize(mic.len() + delay, 0.0);
+    reference.resize(processed.len(), 0.0);
+    // At least a sample, or rates below 50 Hz would give empty blocks
+    let block = (sample_rate as usize * BLOCK_MS / 1000).max(1);
+    for (samples, played) in processed.chunks_mut(block).zip(reference.chunks(block)) {
+        processor.process_with_reference(samples, played);
+    }
+    let processed = &processed[delay..];
+
+    let last_second = mic.len().saturating_sub(sample_rate as usize);
+    println!("Input {}, output {}", level(&mic), level(processed));
+    println!("Last second: input {}, output {}", level(&mic[last_second..]), level(&processed[last_second..]));
+
+    let samples: Vec<i16> = processed.iter().map(|&s| (s * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+                                     .collect();
+    let mut out = WavWriter::create(&options.out, WavFormat { sample_rate, channels: 1 })
+        .map_err(|e| format!("{}: {}", options.out.display(), e))?;
+    out.write(&samples).and_then(|_| out.finish()).map_err(|e| format!("{}: {}", options.out.display(), e))
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("voice-processor: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("voice-processor: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,207 @@
+//! # Configuration for CarPlay Client
+//!
+//! Collects the settings for every layer from the command line.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::link_layer::{LinkConfig, TransportKind};
+use crate::link_layer::device;
+use crate::input_layer::microphone::{MicSelection, MicSource};
+use crate::player_layer::playback_tap::PlaybackTap;
+use crate::player_layer::PlayerConfig;
+use crate::player_layer::audio_output::OutputKind;
+use crate::player_layer::ducking::DuckMode;
+use crate::player_layer::kms::ModeRequest;
+use crate::player_layer::video_sink::SinkKind;
+
+pub const USAGE: &str = "\
+Usage: carplay-client [OPTIONS]
+
+Options:
+  --tcp HOST:PORT    Talk to a dongle emulator or relay at HOST:PORT instead of USB
+  --unix PATH        Talk to a dongle emulator on the Unix socket PATH instead of USB
+  --replay PATH      Play back the session recording PATH instead of talking to a dongle
+  --replay-speed X   Replay X times faster than recorded (default 1, 0 = no waiting)
+  --device VID:PID   Accept this USB id (hex); repeat for several, replaces the defaults
+  --bus N            Only use a dongle on USB bus N
+  --port PATH        Only use a dongle behind hub port chain PATH, e.g. 4.2
+  --serial SERIAL    Only use the dongle with this serial number
+  --usb-transfers N  Keep N USB reads in flight (default 4)
+  --usb-transfer-size BYTES
+                     Size of each USB read (default 49152)
+  --capture PATH     Capture all traffic with the box to the pcapng file PATH;
+                     SIGUSR1 stops and restarts the capture
+  --capture-max-size BYTES
+                     Start a new capture file after BYTES (default 100 MiB, 0 = never)
+  --resolution WxH   Size of the picture asked from the phone (default 1920x720)
+  --fps N            Frame rate asked from the phone (default 60)
+  --windowed         Show video in a window instead of fullscreen
+  --video-sink SINK  Where video goes: mpv (default), file:PATH to save the raw H.264
+                     stream, or null to only check and count frames. Builds with the
+                     openh264 feature also decode to y4m:PATH, png:DIR snapshots, or
+                     kms to draw on a DRM/KMS output without X11 or Wayland
+  --mpv-vo VO        mpv video output to use, e.g. drm to skip the compositor
+  --drm-device PATH  DRM device for kms and mpv's drm output (default /dev/dri/card0)
+  --drm-connector NAME
+                     Connector to show video on, e.g. HDMI-A-1 (default: first connected)
+  --drm-crtc ID      CRTC to drive the connector with, for kms (default: automatic)
+  --drm-mode WxH[@HZ]
+                     Display mode to set (default: the connector's preferred mode)
+  --snapshot-interval SECS
+                     Time between png snapshots (default 5)
+  --audio-output OUTPUT
+                     Where audio goes: alsa[:DEVICE] or pulse[:SINK] in builds with the
+                     alsa or pulse feature (default: whichever is built), wav:PATH to
+                     save everything played, or null
+  --audio-rate HZ    Sample rate audio is mixed and played at (default 48000)
+  --audio-latency MS How much audio each stream keeps buffered against USB hiccups
+                     (default 80)
+  --duck DB|pause    How media makes way for navigation and Siri: lower it by DB
+                     (default 15) or pause it. Calls always mute media
+  --duck-ramp MS     Time media takes to fade fully out or in (default 200)
+  --mic SOURCE       Record Siri and call audio from alsa[:DEVICE] (builds with the alsa
+                     feature) or loop the WAV file at wav:PATH; without it the car
+                     sends no microphone audio
+  --use-mic MIC      Have the box use the car microphone, recorded from --mic, or
+                     its own box one. Without it, the box keeps its own setting
+  --echo-cancel      Cancel the echo of the speakers out of the --mic audio
+  --noise-suppress   Suppress steady background noise in the --mic audio
+  --record PATH      Record the session to PATH, for --replay
+  --list-devices     Print matching dongles and exit
+  --help             Print this message and exit";
+
+/// The picture the phone is asked for, and shown at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    /// Cover the whole screen rather than opening a window.
+    pub fullscreen: bool,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self { width: 1920, height: 720, fps: 60, fullscreen: true }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub link: LinkConfig,
+    pub player: PlayerConfig,
+    /// Copied into `link` and `player`, which both need it.
+    pub display: DisplayConfig,
+    pub list_devices: bool,
+    pub help: bool,
+}
+
+impl Config {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut custom_ids = Vec::new();
+        let (mut replay, mut replay_speed) = (None, 1.0);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--tcp" => config.link.transport = TransportKind::Tcp(value()?),
+                "--unix" => config.link.transport = TransportKind::Unix(value()?.into()),
+                "--replay" => replay = Some(PathBuf::from(value()?)),
+                "--replay-speed" => {
+                    let speed = value()?;
+                    replay_speed = speed.parse().ok().filter(|&speed: &f32| speed >= 0.0 && speed.is_finite())
+                                        .ok_or(format!("invalid replay speed `{}`", speed))?;
+                }
+                "--resolution" => {
+                    let (width, height) = parse_resolution(&value()?)?;
+                    config.display.width = width;
+                    config.display.height = height;
+                }
+                "--fps" => config.display.fps = parse_count(&value()?)? as u32,
+                "--windowed" => config.display.fullscreen = false,
+                "--video-sink" => config.player.sink = SinkKind::parse(&value()?)?,
+                "--snapshot-interval" => {
+                    let secs = parse_count(&value()?)?;
+                    config.player.snapshot_interval = Duration::from_secs(secs as u64);
+                }
+                "--mpv-vo" => config.player.mpv_vo = Some(value()?),
+                "--drm-device" => config.player.drm.device = Some(value()?.into()),
+                "--drm-connector" => config.player.drm.connector = Some(value()?),
+                "--drm-crtc" => {
+                    let crtc = value()?;
+                    config.player.drm.crtc = Some(crtc.parse()
+                        .map_err(|_| format!("invalid CRTC id `{}`", crtc))?);
+                }
+                "--drm-mode" => config.player.drm.mode = Some(ModeRequest::parse(&value()?)?),
+                "--audio-output" => config.player.audio.output = OutputKind::parse(&value()?)?,
+                "--audio-rate" => config.player.audio.sample_rate = parse_count(&value()?)? as u32,
+                "--audio-latency" => {
+                    let ms = parse_count(&value()?)?;
+                    config.player.audio.target_latency = Duration::from_millis(ms as u64);
+                }
+                "--duck" => config.player.audio.ducking.mode = DuckMode::parse(&value()?)?,
+                "--duck-ramp" => {
+                    let ms = parse_count(&value()?)?;
+                    config.player.audio.ducking.ramp = Duration::from_millis(ms as u64);
+                }
+                "--mic" => config.link.microphone = Some(MicSource::parse(&value()?)?),
+                "--use-mic" => config.link.mic_selection = Some(MicSelection::parse(&value()?)?),
+                "--echo-cancel" => config.link.voice.echo_cancellation = true,
+                "--noise-suppress" => config.link.voice.noise_suppression = true,
+                "--record" => config.link.record = Some(value()?.into()),
+                "--device" => custom_ids.push(device::parse_id(&value()?)?),
+                "--bus" => {
+                    let bus = value()?;
+                    config.link.device.bus = Some(bus.parse()
+                        .map_err(|_| format!("invalid bus number `{}`", bus))?);
+                }
+                "--port" => config.link.device.port_path = Some(device::parse_port_path(&value()?)?),
+                "--serial" => config.link.device.serial = Some(value()?),
+                "--usb-transfers" => config.link.bulk_in.transfers = parse_count(&value()?)?,
+                "--usb-transfer-size" => config.link.bulk_in.transfer_size = parse_count(&value()?)?,
+                "--capture" => config.link.capture.path = Some(value()?.into()),
+                "--capture-max-size" => {
+                    let size = value()?;
+                    config.link.capture.max_file_size = size.parse()
+                        .map_err(|_| format!("invalid size `{}`", size))?;
+                }
+                "--list-devices" => config.list_devices = true,
+                "--help" | "-h" => config.help = true,
+                _ => return Err(format!("unknown option `{}`", arg)),
+            }
+        }
+        if (config.link.mic_selection == Some(MicSelection::Car)) && config.link.microphone.is_none() {
+            return Err("--use-mic car needs a microphone to record from, given with --mic".to_string());
+        }
+        if let Some(path) = replay {
+            config.link.transport = TransportKind::Replay { path, speed: replay_speed };
+        }
+        config.link.display = config.display;
+        config.player.display = config.display;
+        if config.link.voice.echo_cancellation {
+            // The echo canceller needs to hear what the player plays
+            let tap = PlaybackTap::default();
+            config.player.playback_tap = Some(tap.clone());
+            config.link.voice.reference = Some(tap);
+        }
+        if !custom_ids.is_empty() {
+            config.link.device.ids = custom_ids;
+        }
+        Ok(config)
+    }
+}
+
+fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("expected WIDTHxHEIGHT, got `{}`", value);
+    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    value.parse().ok().filter(|&count| count > 0)
+         .ok_or(format!("expected a positive number, got `{}`", value))
+}
@@ -0,0 +1,153 @@
+//! # H.264 Helpers for CarPlay Client
+//!
+//! Just enough Annex B parsing to find NAL units and tell which pictures
+//! others depend on. Nothing here decodes video.
+
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR_SLICE: u8 = 5;
+pub const NAL_SEI: u8 = 6;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+
+/// Returns the offsets of every Annex B start code in `data`, along with
+/// the length of the start code found there.
+pub fn start_codes(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            if (i > 0) && (data[i - 1] == 0) {
+                found.push((i - 1, 4));
+            } else {
+                found.push((i, 3));
+            }
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    found
+}
+
+/// The NAL units in `data`, each without its start code.
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let codes = start_codes(data);
+    codes.iter().enumerate().map(|(index, &(start, code_len))| {
+        let end = codes.get(index + 1).map(|&(next, _)| next).unwrap_or(data.len());
+        &data[start + code_len..end]
+    }).filter(|nal| !nal.is_empty()).collect()
+}
+
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal[0] & 0x1f
+}
+
+/// Whether other pictures may be predicted from this NAL unit.
+pub fn is_reference(nal: &[u8]) -> bool {
+    nal[0] & 0x60 != 0
+}
+
+fn is_slice(nal: &[u8]) -> bool {
+    matches!(nal_type(nal), NAL_SLICE | NAL_IDR_SLICE)
+}
+
+/// How much the rest of the stream depends on an access unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Decoding can start here.
+    Idr,
+    /// Later pictures may refer to this one.
+    Reference,
+    /// Nothing refers to this picture; it can be dropped on its own.
+    NonReference,
+}
+
+pub fn classify(access_unit: &[u8]) -> FrameKind {
+    let nals = nal_units(access_unit);
+    if nals.iter().any(|nal| nal_type(nal) == NAL_IDR_SLICE) {
+        FrameKind::Idr
+    } else if nals.iter().any(|nal| is_slice(nal)) && !nals.iter().any(|nal| is_reference(nal)) {
+        FrameKind::NonReference
+    } else {
+        // Parameter sets and anything unrecognised are kept
+        FrameKind::Reference
+    }
+}
+
+/// Groups the NAL units of an H.264 elementary stream into access units,
+/// which is how the dongle hands them out: parameter sets and SEI travel
+/// with the picture that follows them.
+pub fn split_access_units(data: &[u8]) -> Vec<Vec<u8>> {
+    let codes = start_codes(data);
+    let mut units = Vec::new();
+    let mut current: Vec<u8> = Vec::new();
+    let mut has_picture = false;
+    for (index, &(start, code_len)) in codes.iter().enumerate() {
+        let end = codes.get(index + 1).map(|&(next, _)| next).unwrap_or(data.len());
+        let nal = &data[start..end];
+        let (nal_type, first_slice) = match nal.get(code_len..code_len + 2) {
+            // first_mb_in_slice is ue(v), so a leading 1 bit means 0
+            Some(header) => (header[0] & 0x1f, header[1] & 0x80 != 0),
+            None => continue,
+        };
+        let is_slice = (nal_type == NAL_SLICE) || (nal_type == NAL_IDR_SLICE);
+        if has_picture && (!is_slice || first_slice) {
+            units.push(std::mem::take(&mut current));
+            has_picture = false;
+        }
+        current.extend_from_slice(nal);
+        has_picture |= is_slice;
+    }
+    if !current.is_empty() {
+        units.push(current);
+    }
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1f];
+    const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80];
+    const SEI: &[u8] = &[0, 0, 1, 0x06, 0x05, 0x01];
+    /// Slices with first_mb_in_slice 0, starting a picture, and a later
+    /// slice of the same picture.
+    const IDR_FIRST: &[u8] = &[0, 0, 0, 1, 0x65, 0x88, 0x84];
+    const IDR_NEXT: &[u8] = &[0, 0, 0, 1, 0x65, 0x40, 0x84];
+    const P_FIRST: &[u8] = &[0, 0, 1, 0x41, 0x9a, 0x02];
+    const P_NEXT: &[u8] = &[0, 0, 1, 0x41, 0x20, 0x02];
+    const B_FIRST: &[u8] = &[0, 0, 0, 1, 0x01, 0x9e, 0x04];
+    const B_NEXT: &[u8] = &[0, 0, 0, 1, 0x01, 0x30, 0x04];
+
+    #[test]
+    fn start_codes_of_both_lengths() {
+        let data = [SPS, P_FIRST].concat();
+        assert_eq!(start_codes(&data), vec![(0, 4), (SPS.len(), 3)]);
+        assert_eq!(nal_units(&data), vec![&SPS[4..], &P_FIRST[3..]]);
+    }
+
+    #[test]
+    fn classify_access_units() {
+        assert_eq!(classify(&[SPS, PPS, IDR_FIRST, IDR_NEXT].concat()), FrameKind::Idr);
+        assert_eq!(classify(&[P_FIRST, P_NEXT].concat()), FrameKind::Reference);
+        assert_eq!(classify(&[B_FIRST, B_NEXT].concat()), FrameKind::NonReference);
+        // SEI is never a reference, and doesn't make the picture one
+        assert_eq!(classify(&[SEI, B_FIRST].concat()), FrameKind::NonReference);
+        assert_eq!(classify(&[SPS, PPS].concat()), FrameKind::Reference);
+    }
+
+    #[test]
+    fn split_multi_slice_pictures() {
+        let stream = [SPS, PPS, IDR_FIRST, IDR_NEXT, P_FIRST, P_NEXT, SEI, B_FIRST, B_NEXT, P_FIRST].concat();
+        let units = split_access_units(&stream);
+        assert_eq!(units, vec![
+            [SPS, PPS, IDR_FIRST, IDR_NEXT].concat(),
+            [P_FIRST, P_NEXT].concat(),
+            [SEI, B_FIRST, B_NEXT].concat(),
+            P_FIRST.to_vec(),
+        ]);
+        assert_eq!(units.iter().map(|unit| classify(unit)).collect::<Vec<_>>(),
+                   vec![FrameKind::Idr, FrameKind::Reference, FrameKind::NonReference, FrameKind::Reference]);
+    }
+}
@@ -2,17 +2,27 @@
 //! 
 //! Handles touch input for devices equipped with a libinput compatible digitizer 
 //! and "keyboard" input for rotary dials, buttons, and other non touch control
-//! surfaces. Potential plans to include microphone input for siri and phone calls.
+//! surfaces. Microphone input for Siri and phone calls is in `microphone`, which
+//! the link layer drives since it sees when the box wants audio.
 
 use std::fs::{File, OpenOptions};
-use std::os::unix::{fs::OpenOptionsExt, io::{RawFd, FromRawFd, IntoRawFd}};
+use std::io;
+use std::os::unix::{fs::OpenOptionsExt, io::{AsRawFd, RawFd, FromRawFd, IntoRawFd}};
 use std::path::Path;
 use input::{Libinput, LibinputInterface};
 use std::sync::mpsc::{Sender};
 use std::thread;
 
+use crate::link_layer::LinkCommand;
+use crate::signal;
+
+pub mod echo_canceller;
+pub mod microphone;
+pub mod noise_suppressor;
+pub mod voice_processing;
+
 extern crate libc;
-use libc::{O_RDONLY, O_RDWR, O_WRONLY};
+use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
 
 pub struct InputLayer {
     input_ctx: Libinput,
@@ -30,8 +40,8 @@ impl LibinputInterface for InputInterface {
     fn open_restricted(&mut self, path: &Path, flags: i32) -> Result<RawFd, i32> {
         OpenOptions::new()
             .custom_flags(flags)
-            .read((flags & O_RDONLY != 0) | (flags & O_RDWR != 0))
-            .write((flags & O_WRONLY != 0) | (flags & O_RDWR != 0))
+            .read((flags & O_ACCMODE == O_RDONLY) | (flags & O_ACCMODE == O_RDWR))
+            .write((flags & O_ACCMODE == O_WRONLY) | (flags & O_ACCMODE == O_RDWR))
             .open(path)
             .map(|file| file.into_raw_fd())
             .map_err(|err| err.raw_os_error().unwrap())
@@ -44,21 +54,38 @@ impl LibinputInterface for InputInterface {
 }
 
 impl InputLayer {
-    pub fn new() -> Self {
+    fn new() -> io::Result<Self> {
         let mut input_ctx = Libinput::new_with_udev(InputInterface);
-        input_ctx.udev_assign_seat("seat0").unwrap();
-        Self {input_ctx}
+        input_ctx.udev_assign_seat("seat0")
+                 .map_err(|_| io::Error::other("could not assign seat0"))?;
+        Ok(Self {input_ctx})
     }
     // pub fn dispatch(&mut self) -> Result<(), std::io::Error> {
     //     self.input_ctx.dispatch()
     // }
 }
 
-pub fn input_thread(_tx: Sender<()>) -> std::thread::JoinHandle<()> {
+/// How long to wait for input before checking for shutdown again.
+const POLL_TIMEOUT_MS: i32 = 100;
+
+pub fn input_thread(_tx: Sender<LinkCommand>) -> std::thread::JoinHandle<()> {
     thread::spawn(move || {
-        let mut input_layer = InputLayer::new();
-        loop {
-            input_layer.input_ctx.dispatch().unwrap();
+        let mut input_layer = match InputLayer::new() {
+            Ok(input_layer) => input_layer,
+            Err(e) => {
+                println!("Input disabled: {}", e);
+                return;
+            }
+        };
+        let mut pollfd = libc::pollfd { fd: input_layer.input_ctx.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        while !signal::shutdown_requested() {
+            if unsafe { libc::poll(&mut pollfd, 1, POLL_TIMEOUT_MS) } <= 0 {
+                continue;
+            }
+            if let Err(e) = input_layer.input_ctx.dispatch() {
+                println!("Stopping input: {}", e);
+                return;
+            }
             for _event in &mut input_layer.input_ctx {
                 
             }
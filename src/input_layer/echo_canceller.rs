@@ -0,0 +1,79 @@
+//! # Echo Canceller for CarPlay Client
+//!
+//! With the car's microphone, whatever the speakers play is picked up
+//! again and the other end of a call hears themselves. A normalised LMS
+//! filter learns the path from the speakers to the microphone, predicts the
+//! echo from what was played, and subtracts it. While the driver talks
+//! over the far end, the filter stops learning, so it doesn't learn to
+//! cancel the driver too.
+
+use std::time::Duration;
+
+/// Longest speaker to microphone delay that can be cancelled, output
+/// buffering included.
+pub const FILTER_LENGTH: Duration = Duration::from_millis(128);
+
+/// Fraction of the error the filter corrects for per sample. Smaller
+/// converges slower, but is thrown off less by road noise.
+const STEP_SIZE: f32 = 0.1;
+
+/// Keeps quiet passages from blowing up the step, in squared full scale
+/// per tap.
+const REGULARISATION: f32 = 1e-5;
+
+/// The microphone louder than this fraction of the loudest recent
+/// playback can't be echo alone; the driver must be talking. Car speakers
+/// are close to the microphone, so the echo comes back barely weaker.
+const DOUBLE_TALK_THRESHOLD: f32 = 0.7;
+
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    /// The last `weights.len()` reference samples, twice over so the
+    /// newest window is always one contiguous slice.
+    history: Vec<f32>,
+    /// Where the next reference sample goes in the first copy.
+    head: usize,
+    /// Sum of squares over the window.
+    power: f32,
+}
+
+impl EchoCanceller {
+    pub fn new(sample_rate: u32) -> Self {
+        let length = (FILTER_LENGTH.as_secs_f64() * sample_rate as f64) as usize;
+        Self { weights: vec![0.0; length], history: vec![0.0; length * 2], head: 0, power: 0.0 }
+    }
+
+    /// Removes the echo of `reference` from `mic`, both mono, full scale
+    /// at 1.0, and taken over the same span of time.
+    pub fn process(&mut self, mic: &mut [f32], reference: &[f32]) {
+        let length = self.weights.len();
+        if length == 0 {
+            return;
+        }
+        let loudest = reference.iter().chain(&self.history[..length])
+                               .fold(0.0f32, |loudest, &x| loudest.max(x.abs()));
+        // Checked a block at a time; road noise trips it in quiet
+        // passages, where there is nothing to learn anyway
+        let double_talk = mic.iter().any(|&d| d.abs() > DOUBLE_TALK_THRESHOLD * loudest);
+        // Start each block from an exact sum, so rounding can't build up
+        self.power = self.history[..length].iter().map(|x| x * x).sum();
+        for (sample, &x) in mic.iter_mut().zip(reference) {
+            let oldest = self.history[self.head];
+            self.power = (self.power + x * x - oldest * oldest).max(0.0);
+            self.history[self.head] = x;
+            self.history[self.head + length] = x;
+            self.head = (self.head + 1) % length;
+            // Oldest first, matching the weights' order
+            let window = &self.history[self.head..self.head + length];
+            let echo: f32 = self.weights.iter().zip(window).map(|(w, x)| w * x).sum();
+            let error = *sample - echo;
+            if !double_talk {
+                let step = STEP_SIZE * error / (self.power + REGULARISATION * length as f32);
+                for (weight, x) in self.weights.iter_mut().zip(window) {
+                    *weight += step * x;
+                }
+            }
+            *sample = error;
+        }
+    }
+}
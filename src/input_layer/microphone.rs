@@ -0,0 +1,294 @@
+//! # Microphone for CarPlay Client
+//!
+//! Records the car's microphone while Siri is listening or a call is up,
+//! and sends it to the box as `Audio` messages in the format the box asked
+//! for with its last `InputConfig` command. The source is an ALSA device,
+//! or a WAV file played in a loop for testing without one. The box can use
+//! its own microphone instead, in which case nothing is recorded here. On
+//! the way, `voice_processing` can take out the echo of the speakers and
+//! background noise.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "alsa")]
+use crate::alsa;
+use crate::link_layer::box_protocol::{
+    audio_format, Audio, AudioCommand, AUDIO_TYPE_MICROPHONE,
+    BUTTON_USE_BOX_MIC, BUTTON_USE_CAR_MIC,
+};
+use crate::player_layer::resampler::Resampler;
+use crate::wav;
+use super::voice_processing::{VoiceConfig, VoiceProcessor};
+
+/// 16 kHz mono, which is what Siri wants, until the box says otherwise.
+pub const DEFAULT_DECODE_TYPE: u32 = 5;
+
+/// How much audio goes into each message.
+const CHUNK: Duration = Duration::from_millis(20);
+
+/// Asked of ALSA; a little more than a chunk, so none is lost between
+/// reads.
+#[cfg(feature = "alsa")]
+const CAPTURE_LATENCY: Duration = Duration::from_millis(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MicSource {
+    /// An ALSA capture device.
+    Alsa(String),
+    /// A WAV file, looped.
+    Wav(PathBuf),
+}
+
+impl MicSource {
+    /// `alsa[:DEVICE]` or `wav:PATH`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let alsa = |device: &str| if cfg!(feature = "alsa") {
+            Ok(MicSource::Alsa(device.into()))
+        } else {
+            Err("the alsa microphone needs a build with the alsa feature".to_string())
+        };
+        match value.split_once(':') {
+            None if value == "alsa" => alsa("default"),
+            Some(("alsa", device)) if !device.is_empty() => alsa(device),
+            Some(("wav", path)) if !path.is_empty() => Ok(MicSource::Wav(path.into())),
+            _ => Err(format!("unknown microphone `{}`, expected alsa[:DEVICE] or wav:PATH", value)),
+        }
+    }
+}
+
+/// Which microphone the box listens to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicSelection {
+    /// Ours, recorded from the `MicSource`.
+    Car,
+    /// The one built into the box.
+    Box,
+}
+
+impl MicSelection {
+    /// `car` or `box`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "car" => Ok(MicSelection::Car),
+            "box" => Ok(MicSelection::Box),
+            _ => Err(format!("unknown microphone `{}`, expected car or box", value)),
+        }
+    }
+
+    /// The `ButtonCtl` command that selects it.
+    pub fn button(self) -> u32 {
+        match self {
+            MicSelection::Car => BUTTON_USE_CAR_MIC,
+            MicSelection::Box => BUTTON_USE_BOX_MIC,
+        }
+    }
+}
+
+impl fmt::Display for MicSelection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MicSelection::Car => "car",
+            MicSelection::Box => "box",
+        })
+    }
+}
+
+/// Takes interleaved samples in real time.
+trait MicInput {
+    /// Blocks until `samples` is full.
+    fn read(&mut self, samples: &mut [i16]) -> io::Result<()>;
+}
+
+#[cfg(feature = "alsa")]
+impl MicInput for alsa::Pcm {
+    fn read(&mut self, samples: &mut [i16]) -> io::Result<()> {
+        alsa::Pcm::read(self, samples)
+    }
+}
+
+/// A WAV file converted to the wanted format up front, and handed out at
+/// the pace a real microphone would.
+struct WavInput {
+    samples: Vec<i16>,
+    position: usize,
+    /// Duration of one interleaved sample.
+    sample_time: Duration,
+    next: Instant,
+}
+
+impl WavInput {
+    fn open(path: &Path, rate: u32, channels: u16) -> io::Result<Self> {
+        let file = wav::read(path)?;
+        let mut samples = Vec::new();
+        Resampler::new(file.format.sample_rate, file.format.channels, rate, channels)
+            .process(&file.samples, &mut samples);
+        if samples.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no samples"));
+        }
+        let sample_time = Duration::from_secs(1) / (rate * channels as u32);
+        Ok(Self { samples, position: 0, sample_time, next: Instant::now() })
+    }
+}
+
+impl MicInput for WavInput {
+    fn read(&mut self, samples: &mut [i16]) -> io::Result<()> {
+        for sample in samples.iter_mut() {
+            *sample = self.samples[self.position];
+            self.position = (self.position + 1) % self.samples.len();
+        }
+        self.next += self.sample_time * samples.len() as u32;
+        thread::sleep(self.next.saturating_duration_since(Instant::now()));
+        Ok(())
+    }
+}
+
+fn open_input(source: &MicSource, rate: u32, channels: u16) -> io::Result<Box<dyn MicInput>> {
+    Ok(match source {
+        #[cfg(feature = "alsa")]
+        MicSource::Alsa(device) => Box::new(alsa::Pcm::open_capture(device, rate, channels, CAPTURE_LATENCY)?),
+        #[cfg(not(feature = "alsa"))]
+        MicSource::Alsa(_) => {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "built without the alsa feature"));
+        }
+        MicSource::Wav(path) => Box::new(WavInput::open(path, rate, channels)?),
+    })
+}
+
+struct Recording {
+    running: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// Follows the box's audio commands and records while it wants audio.
+pub struct Microphone {
+    source: Option<MicSource>,
+    voice: VoiceConfig,
+    /// Whether the box takes its audio from us rather than another
+    /// microphone.
+    selected: bool,
+    decode_type: u32,
+    siri: bool,
+    call: bool,
+    /// Passes each chunk on; returns false once nobody is listening.
+    deliver: Arc<dyn Fn(Audio) -> bool + Send + Sync>,
+    recording: Option<Recording>,
+}
+
+impl Microphone {
+    /// Without a source, nothing is recorded.
+    pub fn new<F>(source: Option<MicSource>, voice: VoiceConfig, deliver: F) -> Self
+        where F: Fn(Audio) -> bool + Send + Sync + 'static {
+        Self {
+            source,
+            voice,
+            selected: true,
+            decode_type: DEFAULT_DECODE_TYPE,
+            siri: false,
+            call: false,
+            deliver: Arc::new(deliver),
+            recording: None,
+        }
+    }
+
+    /// Whether there is anything to record from.
+    pub fn has_source(&self) -> bool {
+        self.source.is_some()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// `decode_type` is the one of the `Audio` message the command came in.
+    pub fn handle(&mut self, command: AudioCommand, decode_type: u32) {
+        match command {
+            AudioCommand::InputConfig => {
+                if audio_format(decode_type).is_none() || decode_type == self.decode_type {
+                    return;
+                }
+                self.decode_type = decode_type;
+                // Carry on in the new format
+                self.stop_recording();
+            }
+            AudioCommand::SiriStart => self.siri = true,
+            AudioCommand::SiriStop => self.siri = false,
+            AudioCommand::PhoneCallStart => self.call = true,
+            AudioCommand::PhoneCallStop => self.call = false,
+            _ => return,
+        }
+        self.update();
+    }
+
+    /// Records from now on if the box is to use the car's microphone, and
+    /// stops otherwise.
+    pub fn select(&mut self, selection: MicSelection) {
+        self.selected = selection == MicSelection::Car;
+        self.update();
+    }
+
+    /// Forgets about Siri and calls, as when the box goes away.
+    pub fn reset(&mut self) {
+        self.siri = false;
+        self.call = false;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let wanted = self.selected && (self.siri || self.call);
+        match (&self.source, &self.recording) {
+            (Some(source), None) if wanted => self.start_recording(source.clone()),
+            (_, Some(_)) if !wanted => self.stop_recording(),
+            _ => {},
+        }
+    }
+
+    fn start_recording(&mut self, source: MicSource) {
+        let (rate, channels) = audio_format(self.decode_type).expect("checked by handle");
+        let running = Arc::new(AtomicBool::new(true));
+        let (decode_type, deliver, thread_running) = (self.decode_type, self.deliver.clone(), running.clone());
+        // Made afresh for each recording, so the echo canceller starts in
+        // step with playback
+        let mut processor = Some(&self.voice).filter(|voice| voice.is_enabled())
+                                             .map(|voice| VoiceProcessor::new(voice, rate));
+        println!("Recording the microphone at {} Hz, {} channels", rate, channels);
+        let thread = thread::spawn(move || {
+            let mut input = match open_input(&source, rate, channels) {
+                Ok(input) => input,
+                Err(e) => return println!("Could not open the microphone: {}", e),
+            };
+            let mut chunk = vec![0i16; (rate as usize * CHUNK.as_millis() as usize / 1000) * channels as usize];
+            while thread_running.load(Ordering::Relaxed) {
+                if let Err(e) = input.read(&mut chunk) {
+                    return println!("Microphone stopped: {}", e);
+                }
+                if let Some(processor) = processor.as_mut() {
+                    processor.process(&mut chunk, channels);
+                }
+                if !deliver(Audio::new(decode_type, AUDIO_TYPE_MICROPHONE, wav::to_bytes(&chunk))) {
+                    return;
+                }
+            }
+        });
+        self.recording = Some(Recording { running, thread });
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            recording.running.store(false, Ordering::Relaxed);
+            let _ = recording.thread.join();
+            println!("Stopped recording the microphone");
+        }
+    }
+}
+
+impl Drop for Microphone {
+    fn drop(&mut self) {
+        self.stop_recording();
+    }
+}
@@ -0,0 +1,206 @@
+//! # Noise Suppressor for CarPlay Client
+//!
+//! Road, engine and fan noise are steady where speech is not. The signal
+//! is split into overlapping, windowed frames. In each frequency bin the
+//! noise level is taken to be the quietest the bin has been lately, and
+//! that much is subtracted from the frame's spectrum before it is put back
+//! together.
+
+use std::f32::consts::PI;
+use std::time::Duration;
+
+/// Rough length of a frame; rounded down to a power of two samples.
+const FRAME: Duration = Duration::from_millis(32);
+
+/// How much of each frame's power carries over into the smoothed
+/// spectrum. Smoothing keeps the subtraction from leaving warbling
+/// "musical noise" behind.
+const POWER_SMOOTHING: f32 = 0.6;
+
+/// The same for the spectrum the noise is tracked on. Heavier, since a
+/// single frame's power swings far below the average noise.
+const NOISE_SMOOTHING: f32 = 0.9;
+
+/// The noise estimate is the quietest a bin has been over between half
+/// this and all of it. Speech pauses for breath well within it, so speech
+/// doesn't count as noise, and the estimate catches up with louder noise
+/// in this long at most.
+const NOISE_WINDOW: Duration = Duration::from_millis(1500);
+
+/// The quietest moments are quieter than the noise on average, so it is
+/// over-subtracted to make up, and to clear most of it.
+const OVER_SUBTRACTION: f32 = 3.0;
+
+/// What is left of a bin that is all noise, as an amplitude gain; lower
+/// sounds cleaner but more processed.
+const GAIN_FLOOR: f32 = 0.1;
+
+pub struct NoiseSuppressor {
+    size: usize,
+    hop: usize,
+    /// Square root of a periodic Hann window, applied before and after,
+    /// so that frames overlapping by half add back up to the input.
+    window: Vec<f32>,
+    /// The latest `size` input samples, once there are that many.
+    frame: Vec<f32>,
+    /// New input not yet processed.
+    input: Vec<f32>,
+    /// Processed output waiting to be handed out.
+    output: Vec<f32>,
+    /// Tail of the previous frame, still to be added to the next.
+    overlap: Vec<f32>,
+    smoothed: Vec<f32>,
+    averaged: Vec<f32>,
+    /// Quietest averaged power per bin in this half of the noise window,
+    /// and in the half before.
+    block_minimum: Vec<f32>,
+    previous_minimum: Vec<f32>,
+    block_frames: usize,
+    frames_in_block: usize,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl NoiseSuppressor {
+    pub fn new(sample_rate: u32) -> Self {
+        let wanted = ((FRAME.as_secs_f64() * sample_rate as f64) as usize).max(4);
+        let size = 1 << (usize::BITS - 1 - wanted.leading_zeros());
+        let hop = size / 2;
+        let window = (0..size).map(|n| (0.5 - 0.5 * (2.0 * PI * n as f32 / size as f32).cos()).sqrt()).collect();
+        let block_frames = ((NOISE_WINDOW.as_secs_f64() / 2.0 * sample_rate as f64) as usize / hop).max(1);
+        let bins = size / 2 + 1;
+        Self {
+            size,
+            hop,
+            window,
+            frame: Vec::with_capacity(size),
+            input: Vec::new(),
+            // Enough to answer for every call before the first frame is done
+            output: vec![0.0; size],
+            overlap: vec![0.0; size],
+            smoothed: vec![0.0; bins],
+            averaged: vec![0.0; bins],
+            block_minimum: vec![f32::MAX; bins],
+            previous_minimum: vec![f32::MAX; bins],
+            block_frames,
+            frames_in_block: 0,
+            re: vec![0.0; size],
+            im: vec![0.0; size],
+        }
+    }
+
+    /// Samples the output runs behind the input.
+    pub fn delay(&self) -> usize {
+        self.size
+    }
+
+    /// Cleans up mono samples in place, full scale at 1.0. The output runs
+    /// `delay` samples behind the input.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        self.input.extend_from_slice(samples);
+        while self.input.len() >= self.hop {
+            let started = self.frame.len() == self.size;
+            if started {
+                self.frame.drain(..self.hop);
+            }
+            self.frame.extend(self.input.drain(..self.hop));
+            if self.frame.len() == self.size {
+                // The first frame sets where the noise estimate starts
+                self.process_frame(started);
+            }
+        }
+        let ready = samples.len().min(self.output.len());
+        for (sample, processed) in samples.iter_mut().zip(self.output.drain(..ready)) {
+            *sample = processed;
+        }
+    }
+
+    fn process_frame(&mut self, started: bool) {
+        let size = self.size;
+        for (n, (re, im)) in self.re.iter_mut().zip(self.im.iter_mut()).enumerate() {
+            *re = self.frame[n] * self.window[n];
+            *im = 0.0;
+        }
+        fft(&mut self.re, &mut self.im, false);
+
+        for bin in 0..=size / 2 {
+            let power = self.re[bin] * self.re[bin] + self.im[bin] * self.im[bin];
+            let smoothed = POWER_SMOOTHING * self.smoothed[bin] + (1.0 - POWER_SMOOTHING) * power;
+            self.smoothed[bin] = smoothed;
+            self.averaged[bin] = match started {
+                true => NOISE_SMOOTHING * self.averaged[bin] + (1.0 - NOISE_SMOOTHING) * power,
+                false => power,
+            };
+            self.block_minimum[bin] = self.block_minimum[bin].min(self.averaged[bin]);
+            let noise = self.block_minimum[bin].min(self.previous_minimum[bin]);
+            let gain = match smoothed > 0.0 {
+                true => (1.0 - OVER_SUBTRACTION * noise / smoothed).max(GAIN_FLOOR * GAIN_FLOOR).sqrt(),
+                false => GAIN_FLOOR,
+            };
+            self.re[bin] *= gain;
+            self.im[bin] *= gain;
+            // The upper half mirrors the lower for real input
+            if bin != 0 && bin != size / 2 {
+                self.re[size - bin] *= gain;
+                self.im[size - bin] *= gain;
+            }
+        }
+
+        self.frames_in_block += 1;
+        if self.frames_in_block == self.block_frames {
+            self.frames_in_block = 0;
+            std::mem::swap(&mut self.previous_minimum, &mut self.block_minimum);
+            self.block_minimum.fill(f32::MAX);
+        }
+
+        fft(&mut self.re, &mut self.im, true);
+        for n in 0..size {
+            self.overlap[n] += self.re[n] * self.window[n];
+        }
+        self.output.extend(self.overlap.drain(..self.hop));
+        self.overlap.resize(size, 0.0);
+    }
+}
+
+/// In-place radix-2 FFT of a power of two length. The inverse is scaled,
+/// so a round trip gives back the input.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_re, w_im) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+    if inverse {
+        for (re, im) in re.iter_mut().zip(im.iter_mut()) {
+            *re /= n as f32;
+            *im /= n as f32;
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! # Voice Processing for CarPlay Client
+//!
+//! Cleans up the car's microphone on its way to the box: first the echo of
+//! what the speakers play is cancelled, then steady background noise is
+//! suppressed. Both are off unless asked for. The `voice-processor` tool
+//! runs the same stages over WAV files.
+
+use crate::player_layer::playback_tap::PlaybackTap;
+use crate::player_layer::resampler::Resampler;
+use super::echo_canceller::EchoCanceller;
+use super::noise_suppressor::NoiseSuppressor;
+
+#[derive(Debug, Clone, Default)]
+pub struct VoiceConfig {
+    pub echo_cancellation: bool,
+    pub noise_suppression: bool,
+    /// What the speakers play, which echo cancellation works from.
+    pub reference: Option<PlaybackTap>,
+}
+
+impl VoiceConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.echo_cancellation || self.noise_suppression
+    }
+}
+
+/// Reads the playback tap alongside the microphone, at its rate.
+struct ReferenceReader {
+    tap: PlaybackTap,
+    sample_rate: u32,
+    resampler: Option<Resampler>,
+    /// Next tap sample to read.
+    position: Option<u64>,
+    raw: Vec<i16>,
+    converted: Vec<i16>,
+}
+
+impl ReferenceReader {
+    fn new(tap: PlaybackTap, sample_rate: u32) -> Self {
+        Self { tap, sample_rate, resampler: None, position: None, raw: Vec::new(), converted: Vec::new() }
+    }
+
+    /// The playback to go with `out.len()` samples just recorded.
+    fn read(&mut self, out: &mut [f32]) {
+        let tap_rate = self.tap.sample_rate();
+        if tap_rate == 0 {
+            return out.fill(0.0);
+        }
+        if self.resampler.as_ref().is_none_or(|resampler| resampler.input_format().0 != tap_rate) {
+            self.resampler = Some(Resampler::new(tap_rate, 1, self.sample_rate, 1));
+            self.position = None;
+            self.converted.clear();
+        }
+        let sample_rate = self.sample_rate;
+        let to_tap = |samples: usize| (samples as f64 * tap_rate as f64 / sample_rate as f64).ceil() as usize;
+        // What was just recorded went on while the latest playback was
+        // handed to the output, and heard a little after
+        let tap = &self.tap;
+        let position = self.position.get_or_insert_with(|| tap.position().saturating_sub(to_tap(out.len()) as u64));
+        while self.converted.len() < out.len() {
+            self.raw.resize(to_tap(out.len() - self.converted.len()).max(1), 0);
+            tap.read(*position, &mut self.raw);
+            *position += self.raw.len() as u64;
+            self.resampler.as_mut().unwrap().process(&self.raw, &mut self.converted);
+        }
+        let len = out.len();
+        for (sample, converted) in out.iter_mut().zip(self.converted.drain(..len)) {
+            *sample = converted as f32 / 32768.0;
+        }
+    }
+}
+
+pub struct VoiceProcessor {
+    echo: Option<EchoCanceller>,
+    noise: Option<NoiseSuppressor>,
+    reference: Option<ReferenceReader>,
+    mono: Vec<f32>,
+    played: Vec<f32>,
+}
+
+impl VoiceProcessor {
+    pub fn new(config: &VoiceConfig, sample_rate: u32) -> Self {
+        let reference = config.reference.clone().filter(|_| config.echo_cancellation)
+                                               .map(|tap| ReferenceReader::new(tap, sample_rate));
+        Self {
+            echo: config.echo_cancellation.then(|| EchoCanceller::new(sample_rate)),
+            noise: config.noise_suppression.then(|| NoiseSuppressor::new(sample_rate)),
+            reference,
+            mono: Vec::new(),
+            played: Vec::new(),
+        }
+    }
+
+    /// Cleans up interleaved samples in place, against what the speakers
+    /// are playing meanwhile. Channels are processed mixed down to one.
+    pub fn process(&mut self, samples: &mut [i16], channels: u16) {
+        let channels = channels.max(1) as usize;
+        let mut mono = std::mem::take(&mut self.mono);
+        mono.clear();
+        mono.extend(samples.chunks_exact(channels)
+                           .map(|frame| frame.iter().map(|&s| s as f32).sum::<f32>() / channels as f32 / 32768.0));
+        let mut played = std::mem::take(&mut self.played);
+        played.resize(mono.len(), 0.0);
+        match self.reference.as_mut() {
+            Some(reference) => reference.read(&mut played),
+            None => played.fill(0.0),
+        }
+        self.process_with_reference(&mut mono, &played);
+        for (frame, &sample) in samples.chunks_exact_mut(channels).zip(&mono) {
+            frame.fill((sample * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+        }
+        self.mono = mono;
+        self.played = played;
+    }
+
+    /// Cleans up mono samples in place, full scale at 1.0, given what was
+    /// played over the same span of time.
+    pub fn process_with_reference(&mut self, mic: &mut [f32], reference: &[f32]) {
+        if let Some(echo) = self.echo.as_mut() {
+            echo.process(mic, reference);
+        }
+        if let Some(noise) = self.noise.as_mut() {
+            noise.process(mic);
+        }
+    }
+
+    /// Samples the output runs behind the input.
+    pub fn delay(&self) -> usize {
+        self.noise.as_ref().map_or(0, NoiseSuppressor::delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 16000;
+    /// Samples handed over at a time, as the microphone does.
+    const BLOCK: usize = 320;
+
+    /// Repeatable white noise, full scale at `level`.
+    fn noise(len: usize, level: f32, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 23) as f32 * 2.0 * level - level
+        }).collect()
+    }
+
+    fn db(samples: &[f32]) -> f32 {
+        let power = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+        10.0 * power.max(1e-12).log10()
+    }
+
+    /// Level of the `frequency` component alone, in dB.
+    fn tone_db(samples: &[f32], frequency: f32) -> f32 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (n, &s) in samples.iter().enumerate() {
+            let phase = 2.0 * PI * frequency * n as f32 / RATE as f32;
+            re += s * phase.cos();
+            im += s * phase.sin();
+        }
+        let amplitude = 2.0 * (re * re + im * im).sqrt() / samples.len() as f32;
+        20.0 * amplitude.max(1e-6).log10()
+    }
+
+    /// Runs `mic` through a processor, returning the output lined up with
+    /// the input.
+    fn run(config: VoiceConfig, mic: &[f32], reference: &[f32]) -> Vec<f32> {
+        let mut processor = VoiceProcessor::new(&config, RATE);
+        let delay = processor.delay();
+        let mut out = mic.to_vec();
+        out.resize(mic.len() + delay, 0.0);
+        let mut reference = reference.to_vec();
+        reference.resize(out.len(), 0.0);
+        for (samples, played) in out.chunks_mut(BLOCK).zip(reference.chunks(BLOCK)) {
+            processor.process_with_reference(samples, played);
+        }
+        out.drain(..delay);
+        out
+    }
+
+    #[test]
+    fn echo_is_cancelled() {
+        let seconds = 6;
+        let played = noise(RATE as usize * seconds, 0.3, 1);
+        // A direct path and a couple of reflections off the cabin
+        let response = [(40, 0.4), (95, -0.15), (260, 0.08)];
+        let mut mic = noise(played.len(), 0.001, 2);
+        for (n, sample) in mic.iter_mut().enumerate() {
+            for &(delay, gain) in &response {
+                if n >= delay {
+                    *sample += gain * played[n - delay];
+                }
+            }
+        }
+        let config = VoiceConfig { echo_cancellation: true, ..Default::default() };
+        let out = run(config, &mic, &played);
+        let last_second = mic.len() - RATE as usize;
+        let reduction = db(&mic[last_second..]) - db(&out[last_second..]);
+        assert!(reduction > 15.0, "echo only reduced by {:.1} dB", reduction);
+    }
+
+    #[test]
+    fn near_end_speech_is_kept() {
+        // Nothing was played, so there is nothing to cancel
+        let tone: Vec<f32> = (0..RATE as usize * 2).map(|n| 0.3 * (2.0 * PI * 440.0 * n as f32 / RATE as f32).sin())
+                                                   .collect();
+        let config = VoiceConfig { echo_cancellation: true, ..Default::default() };
+        let out = run(config, &tone, &[]);
+        assert!((db(&tone) - db(&out)).abs() < 0.1);
+    }
+
+    #[test]
+    fn steady_noise_is_suppressed() {
+        let len = RATE as usize * 6;
+        let background = noise(len, 0.05, 3);
+        // Bursts of a tone, on and off like speech
+        let frequency = 1000.0;
+        let burst = RATE as usize * 3 / 10;
+        let speech: Vec<f32> = (0..len).map(|n| match (n / burst) % 2 {
+            1 => 0.3 * (2.0 * PI * frequency * n as f32 / RATE as f32).sin(),
+            _ => 0.0,
+        }).collect();
+        let mic: Vec<f32> = background.iter().zip(&speech).map(|(b, s)| b + s).collect();
+        let config = VoiceConfig { noise_suppression: true, ..Default::default() };
+        let out = run(config, &mic, &[]);
+
+        // A pause and a burst, well after the noise estimate has settled
+        let pause = 16 * burst..17 * burst;
+        let talking = 17 * burst..18 * burst;
+        // Frames spill over the edges of a burst
+        let margin = 512;
+        let pause = pause.start + margin..pause.end - margin;
+        let talking = talking.start + margin..talking.end - margin;
+        let reduction = db(&mic[pause.clone()]) - db(&out[pause]);
+        assert!(reduction > 8.0, "noise only reduced by {:.1} dB", reduction);
+        let tone_loss = tone_db(&mic[talking.clone()], frequency) - tone_db(&out[talking], frequency);
+        assert!(tone_loss.abs() < 3.0, "tone changed by {:.1} dB", tone_loss);
+    }
+}
@@ -0,0 +1,17 @@
+//! # CarPlay Client
+//!
+//! Client application for "AutoBox" CarPlay dongles. The layers are exposed
+//! as a library so they can be driven by other front ends (and by the
+//! in-memory transport) as well as by the `carplay-client` binary.
+
+#[cfg(feature = "alsa")]
+pub mod alsa;
+pub mod config;
+pub mod h264;
+pub mod input_layer;
+pub mod link_layer;
+pub mod player_layer;
+#[cfg(feature = "pulse")]
+pub mod pulse;
+pub mod signal;
+pub mod wav;
@@ -4,138 +4,279 @@
 //! application.
 
 use std::vec::Vec;
-use std::sync::mpsc::{Sender, Receiver};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 use std::thread;
 
-extern crate rusb;
-use rusb::*;
+use crate::config::DisplayConfig;
+use crate::input_layer::microphone::{MicSelection, MicSource, Microphone};
+use crate::input_layer::voice_processing::VoiceConfig;
+use crate::signal;
 
-mod box_protocol;
+pub mod box_protocol;
 use box_protocol::*;
 
-const ID_VENDOR:u16 = 0x1314;
-const ID_PRODUCT:u16 = 0x1520;
+pub mod framing;
 
-pub struct LinkLayer {
-    device_handle: DeviceHandle<Context>,
-    ep_in: u8,
-    ep_out: u8,
-    input_layer_rx: Receiver<()>,
-    player_layer_tx: Sender<()>,
-}
+pub mod buffer_pool;
+use buffer_pool::BufferPool;
 
-// TODO: Implement hotplug functionality
-impl LinkLayer {
-    pub fn new(player_layer_tx: Sender<()>, input_layer_rx: Receiver<()>) -> Self {
-        let device = LinkLayer::get_dev().unwrap();
-        let mut device_handle = device.open().unwrap();
+pub mod capture;
+use capture::{Capture, CaptureConfig};
 
-        device_handle.reset().unwrap();
-        device_handle.set_active_configuration(1).unwrap(); // Config 1 is the first valid one
+pub mod recording;
+use recording::Recorder;
 
-        let config_desc = device_handle.device().config_descriptor(0).unwrap(); // Config _index_
-        let iface_desc = config_desc.interfaces()
-                                        .next().unwrap().descriptors()
-                                        .next().unwrap();
+pub mod reader;
+use reader::Reader;
 
-        match device_handle.kernel_driver_active(iface_desc.interface_number()) {
-            Ok(true) => device_handle.detach_kernel_driver(iface_desc.interface_number()).unwrap(),
-            _ => {},
-        }
-        device_handle.claim_interface(iface_desc.interface_number()).unwrap();
+pub mod error;
+pub use error::LinkError;
+
+pub mod transport;
+use transport::{Connector, Transport, TransportError, Result};
+use transport::memory::{MemoryConnector, MemoryTransport};
+use transport::replay::ReplayConnector;
+use transport::socket::{TcpConnector, UnixConnector};
+use transport::usb::{BulkInConfig, UsbConnector};
+pub use transport::usb::list_devices;
+
+mod session;
+pub use session::SessionState;
+use session::*;
+
+pub mod device;
+use device::DeviceFilter;
+
+pub mod writer;
+use writer::Writer;
+
+pub mod stats;
+use stats::{LinkStats, StatsSnapshot};
+
+const SEARCH_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a `LinkEvent::Stats` goes out.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Notifications sent from the link layer to the rest of the client.
+pub enum LinkEvent {
+    StateChanged(SessionState),
+    /// A message from the box, forwarded as soon as it was read.
+    Message(MsgType),
+    /// Link counters, sent every `STATS_INTERVAL`.
+    Stats(StatsSnapshot),
+}
+
+/// Requests from the rest of the client to the link layer.
+pub enum LinkCommand {
+    /// Queue a message for the box.
+    Send(MsgType),
+    /// Capture all traffic to a pcapng file, from now on.
+    StartCapture(PathBuf),
+    StopCapture,
+    /// Switch the box to another microphone.
+    SelectMic(MicSelection),
+}
 
-        let (ep_in, ep_out) = LinkLayer::get_endpoints(&iface_desc).unwrap();
+/// How often the session checks on timeouts when nothing is happening.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-        device_handle.clear_halt(ep_in.address()).unwrap();
-        device_handle.clear_halt(ep_out.address()).unwrap();
+/// Everything the session thread waits on, merged into one channel.
+enum SessionInput {
+    /// A message or read error, tagged with the connection it came from.
+    Read(u64, Result<MsgType>),
+    Command(LinkCommand),
+}
 
-        println!("Claimed Interface # {:#?}", iface_desc.interface_number());
-        println!("In Endpoint: {:#?}", ep_in);
-        println!("Out Endpoint: {:#?}", ep_out);
+#[derive(Debug, Clone, Default)]
+pub enum TransportKind {
+    /// A dongle on the local USB bus, picked by `LinkConfig::device`.
+    #[default]
+    Usb,
+    /// A dongle emulator or remote box at `host:port`.
+    Tcp(String),
+    /// A dongle emulator listening on a Unix domain socket.
+    Unix(PathBuf),
+    /// A session recording, played back at `speed` times real time.
+    Replay { path: PathBuf, speed: f32 },
+}
+
+/// Settings for the link layer; `Default` matches any known dongle.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    pub transport: TransportKind,
+    pub device: DeviceFilter,
+    /// How many USB reads to keep in flight, and how large.
+    pub bulk_in: BulkInConfig,
+    pub capture: CaptureConfig,
+    /// Record the session to this file.
+    pub record: Option<PathBuf>,
+    /// What to ask the box for in `OpenBox`.
+    pub display: DisplayConfig,
+    /// Where to record Siri and call audio from; none sends nothing.
+    pub microphone: Option<MicSource>,
+    /// Which microphone the box should use; none leaves it to the box.
+    pub mic_selection: Option<MicSelection>,
+    /// Echo cancellation and noise suppression for the microphone.
+    pub voice: VoiceConfig,
+}
 
+pub struct LinkLayer {
+    connector: Box<dyn Connector>,
+    transport: Option<Arc<dyn Transport>>,
+    reader: Option<Reader>,
+    writer: Option<Writer>,
+    /// Receive buffers, shared by every connection.
+    pool: BufferPool,
+    /// Bumped on every connect, so reads from an old connection that are
+    /// still queued can be told apart.
+    generation: u64,
+    stats: LinkStats,
+    last_stats: Instant,
+    capture: Capture,
+    /// Where SIGUSR1 starts a capture.
+    capture_path: Option<PathBuf>,
+    recorder: Recorder,
+    display: DisplayConfig,
+    microphone: Microphone,
+    /// Sent to the box on every connect, once chosen.
+    mic_selection: Option<MicSelection>,
+    inputs_tx: Sender<SessionInput>,
+    inputs: Receiver<SessionInput>,
+    state: SessionState,
+    backoff: Backoff,
+    opened_at: Instant,
+    last_video: Instant,
+    input_layer_rx: Option<Receiver<LinkCommand>>,
+    player_layer_tx: Sender<LinkEvent>,
+}
+
+// TODO: Implement hotplug functionality
+impl LinkLayer {
+    /// Sets up the configured transport and, if the box is already there,
+    /// connects to it straight away, so that problems like missing
+    /// permissions are reported up front instead of retried forever.
+    pub fn new(config: LinkConfig, player_layer_tx: Sender<LinkEvent>,
+               input_layer_rx: Receiver<LinkCommand>) -> std::result::Result<Self, LinkError> {
+        let connector: Box<dyn Connector> = match config.transport {
+            TransportKind::Usb => Box::new(UsbConnector::new(config.device, config.bulk_in)?),
+            TransportKind::Tcp(addr) => Box::new(TcpConnector::new(addr)),
+            TransportKind::Unix(path) => Box::new(UnixConnector::new(path)),
+            TransportKind::Replay { path, speed } => Box::new(ReplayConnector::new(path, speed)),
+        };
+        let mut link_layer = LinkLayer::with_connector(connector, player_layer_tx, input_layer_rx);
+        link_layer.display = config.display;
+        link_layer.microphone = Microphone::new(config.microphone, config.voice, link_layer.microphone_sink());
+        if let Some(selection) = config.mic_selection {
+            link_layer.select_mic(selection);
+        }
+        link_layer.capture = Capture::new(config.capture.max_file_size);
+        if let Some(path) = config.capture.path {
+            link_layer.capture.start(&path)
+                .map_err(|source| LinkError::Capture { path: path.clone(), source })?;
+            link_layer.capture_path = Some(path);
+        }
+        if let Some(path) = config.record {
+            link_layer.recorder.start(&path)
+                .map_err(|source| LinkError::Recording { path: path.clone(), source })?;
+        }
+        if let Some(transport) = link_layer.connector.connect()? {
+            link_layer.attach(transport);
+        }
+        Ok(link_layer)
+    }
+    /// Builds a link layer on top of an already connected in-memory
+    /// transport, e.g. one end of `MemoryTransport::pair()`.
+    pub fn with_memory(transport: MemoryTransport, player_layer_tx: Sender<LinkEvent>,
+                       input_layer_rx: Receiver<LinkCommand>) -> Self {
+        LinkLayer::with_connector(Box::new(MemoryConnector::new(transport)),
+                                  player_layer_tx, input_layer_rx)
+    }
+    fn with_connector(connector: Box<dyn Connector>, player_layer_tx: Sender<LinkEvent>,
+                      input_layer_rx: Receiver<LinkCommand>) -> Self {
+        let (inputs_tx, inputs) = mpsc::channel();
+        let pool = BufferPool::default();
+        let microphone = Microphone::new(None, VoiceConfig::default(), |_| false);
         Self {
-            device_handle, 
-            ep_in: ep_in.address(), 
-            ep_out: ep_out.address(), 
-            input_layer_rx, 
+            connector,
+            transport: None,
+            reader: None,
+            writer: None,
+            stats: LinkStats::new(pool.clone()),
+            last_stats: Instant::now(),
+            capture: Capture::new(capture::DEFAULT_MAX_FILE_SIZE),
+            capture_path: None,
+            recorder: Recorder::new(),
+            display: DisplayConfig::default(),
+            microphone,
+            mic_selection: None,
+            pool,
+            generation: 0,
+            inputs_tx,
+            inputs,
+            state: SessionState::Searching,
+            backoff: Backoff::new(),
+            opened_at: Instant::now(),
+            last_video: Instant::now(),
+            input_layer_rx: Some(input_layer_rx),
             player_layer_tx
-        }   
-    }
-    fn get_dev() -> Result<Device<Context>> {
-        let mut device_opt: Option<Device<Context>> = None;
-        loop {
-            match device_opt {
-                Some(ref _device_opt) => break,
-                None => {
-                    let usb_ctx = Context::new().unwrap();
-                    for device in usb_ctx.devices().unwrap().iter() {
-                        let device_desc = device.device_descriptor().unwrap();
-                        if (device_desc.vendor_id() == ID_VENDOR) & 
-                            (device_desc.product_id() == ID_PRODUCT) {
-                            device_opt = Some(device);
-                        }
-                    }
-                }
-            }
         }
-        Ok(device_opt.unwrap())
     }
-    fn get_endpoints<'a>(iface_desc: &'a InterfaceDescriptor) -> 
-                Result<(EndpointDescriptor<'a>, EndpointDescriptor<'a>)> {
-        let mut endpoint_out: Option<EndpointDescriptor> = None;
-        let mut endpoint_in: Option<EndpointDescriptor> = None;
-        for endpoint_desc in iface_desc.endpoint_descriptors() {
-            match endpoint_desc.direction() {
-                Direction::Out => endpoint_out = Some(endpoint_desc),
-                Direction::In => endpoint_in = Some(endpoint_desc),
-            }
-        }
-        let ep_out = endpoint_out.unwrap();
-        let ep_in = endpoint_in.unwrap();
-        Ok((ep_in, ep_out))
+    /// Current link counters.
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+    /// A handle on the counters that stays valid for the life of the link,
+    /// for reading them from another thread.
+    pub fn stats_handle(&self) -> LinkStats {
+        self.stats.clone()
     }
 
-    fn rx_packet(&mut self) -> Result<MsgType> {
-        let mut header_buf: [u8; 16] = [0; 16];
-
-        // (1) We should not unwrap here, we need to handle potential errors
-        self.device_handle.read_bulk(
-            self.ep_in, 
-            &mut header_buf,
-            Duration::new(1,0)
-        ).unwrap();
-        let header: BoxMsgHeader = bincode::deserialize(&header_buf).unwrap();
+    /// Queues microphone audio for the box like any other message.
+    fn microphone_sink(&self) -> impl Fn(Audio) -> bool + Send + Sync + 'static {
+        let inputs = self.inputs_tx.clone();
+        move |audio| inputs.send(SessionInput::Command(LinkCommand::Send(MsgType::Audio(audio)))).is_ok()
+    }
 
-        // Maybe this should be in a separate promote packet function?
-        let mut payload_buf: Vec<u8> = Vec::with_capacity(header.msg_len as usize);
-        // Same as (1)
-        self.device_handle.read_bulk(
-            self.ep_in, 
-            &mut payload_buf,
-            Duration::new(1,0)
-        ).unwrap();
-        let mut buf = header_buf.to_vec();
-        buf.append(&mut payload_buf);
+    /// Tells the box which microphone to use, now if it is connected and
+    /// again whenever it reconnects. Recording here follows along.
+    pub fn select_mic(&mut self, selection: MicSelection) {
+        if (selection == MicSelection::Car) && !self.microphone.has_source() {
+            // The box would wait for audio that never comes
+            return println!("Not using the car microphone: there is nothing to record from (--mic)");
+        }
+        println!("Using the {} microphone", selection);
+        self.mic_selection = Some(selection);
+        self.microphone.select(selection);
+        // Without a box it is sent by start_box instead
+        let _ = self.tx_packet(MsgType::ButtonCtl(ButtonCtl::new(selection.button())));
+    }
 
-        // There should be a check that the packet matches the msg type we can id
-        Ok(MsgType::deserialize(header.msg_type, &buf))
+    fn close(&mut self) {
+        // Nobody to talk to until the box is back
+        self.microphone.reset();
+        // Stop the reader and writer first, they hold the transport too
+        self.reader = None;
+        self.writer = None;
+        // Dropping the transport releases the dongle
+        if self.transport.take().is_some() {
+            println!("Buffer pool: {:?}", self.pool.stats());
+        }
     }
 
-    fn tx_packet<'de, T: BaseBoxMsg<'de>>(&mut self, packet: T) -> Result<usize> {
-        println!("{:#?}", &packet.serialize());
-        // Ok(8 as usize)
-        self.device_handle.write_bulk(
-            self.ep_out, 
-            &packet.serialize(), // !! This is a vector !!
-            Duration::new(1,0)
-        )
+    /// Queues a message for the writer thread.
+    fn tx_packet(&mut self, packet: MsgType) -> Result<()> {
+        let writer = self.writer.as_ref().ok_or(TransportError::Disconnected)?;
+        writer.send(packet);
+        Ok(())
     }
 
-    fn tx_n_packets<'de, T: BaseBoxMsg<'de>>(&mut self, packets: Vec<Box<T>>) -> Result<usize> {
+    fn tx_n_packets(&mut self, packets: Vec<MsgType>) -> Result<usize> {
         let num_packets = packets.len();
         for packet in packets {
-            match self.tx_packet(*packet) {
+            match self.tx_packet(packet) {
                 Ok(_val) => continue,
                 Err(e) => return Err(e)
             }
@@ -149,65 +290,232 @@ impl LinkLayer {
     // fn tx_append_file<T>(&mut self, filename: String, data: T) -> Result<usize> {
     // }
 
-    pub fn start_box(&mut self) {
-        let mut packet_vector: Vec<Box<MsgType>> = Vec::new();
-        let video_data: Vec<u8> = Vec::new();
-        let heartbeat_packet = MsgType::Heartbeat(Heartbeat::new()); 
-        let open_packet = MsgType::OpenBox(OpenBox::new(1920, 720, 60));
-
-        packet_vector.push(Box::new(heartbeat_packet));
-        packet_vector.push(Box::new(open_packet));
-
-        // Bullshit packets so we don't have annoying warnings for now
-        packet_vector.push(Box::new(MsgType::DevPlug(DevPlug::new())));
-        packet_vector.push(Box::new(MsgType::DevUnplug(DevUnplug::new())));
-        packet_vector.push(Box::new(MsgType::Touch(Touch::new())));
-        packet_vector.push(Box::new(MsgType::Video(Video::new(video_data))));
-        packet_vector.push(Box::new(MsgType::Audio(Audio::new())));
-        packet_vector.push(Box::new(MsgType::ButtonCtl(ButtonCtl::new())));
-        packet_vector.push(Box::new(MsgType::BtAddr(BtAddr::new())));
-        packet_vector.push(Box::new(MsgType::BtPin(BtPin::new())));
-        packet_vector.push(Box::new(MsgType::ManInfo(ManInfo::new(-1, -1))));
-        packet_vector.push(Box::new(MsgType::MultiTouch(MultiTouch::new())));
-        packet_vector.push(Box::new(MsgType::SendFile(SendFile::new())));
-        packet_vector.push(Box::new(MsgType::SwVer(SwVer::new())));
-
-        self.tx_n_packets(packet_vector).unwrap();
+    pub fn start_box(&mut self) -> Result<usize> {
+        let mut packet_vector: Vec<MsgType> = vec![
+            MsgType::Heartbeat(Heartbeat::new()),
+            MsgType::OpenBox(OpenBox::new(self.display.width, self.display.height, self.display.fps)),
+        ];
+        if let Some(selection) = self.mic_selection {
+            packet_vector.push(MsgType::ButtonCtl(ButtonCtl::new(selection.button())));
+        }
+        self.tx_n_packets(packet_vector)
     }
     
-    // TODO: Rather than have a separate thread for heartbeat and send message,
-    // it may be more efficient to queue messages and only send a heartbeat
-    // when there are no messages to send and a timeout has been reached. This
-    // is highly dependent on hardware requirements, please double check
-    // feasibility. To accomplish this, there needs to be some degree of 
-    // message coordination.
-    // 
-    // TODO: Perhaps implement message sending in an asynchronous fashion with
-    // the associated timeout to prevent premature device disconnection. Do so
-    // only if it makes sense for an asynchronous architecture here.
     pub fn communicate(&mut self) {
+        match self.inputs.recv_timeout(POLL_INTERVAL) {
+            Ok(input) => self.handle_input(input),
+            Err(RecvTimeoutError::Timeout) => {},
+            // We hold a sender ourselves, so this can't happen
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+        if self.state == SessionState::Recovering {
+            return;
+        }
+        if let Some(e) = self.writer.as_ref().and_then(Writer::error) {
+            return self.fail(e);
+        }
+        self.check_timeouts();
+    }
+
+    fn handle_input(&mut self, input: SessionInput) {
+        match input {
+            SessionInput::Read(generation, result) if generation == self.generation => match result {
+                Ok(msg) => self.handle_packet(msg),
+                Err(e) => self.fail(e),
+            },
+            // Left over from a connection that has since been closed
+            SessionInput::Read(..) => {},
+            SessionInput::Command(LinkCommand::Send(msg)) => {
+                // Without a box there is nobody to send to, and stale input
+                // is worse than none once it comes back
+                let _ = self.tx_packet(msg);
+            }
+            SessionInput::Command(LinkCommand::StartCapture(path)) => self.start_capture(path),
+            SessionInput::Command(LinkCommand::StopCapture) => self.capture.stop(),
+            SessionInput::Command(LinkCommand::SelectMic(selection)) => self.select_mic(selection),
+        }
+    }
+
+    /// Handles whatever arrived while the session wasn't listening.
+    fn drain_inputs(&mut self) {
+        while let Ok(input) = self.inputs.try_recv() {
+            self.handle_input(input);
+        }
+    }
+
+    /// Moves commands from the other layers onto the session's own channel,
+    /// so the session wakes for them and for reads alike.
+    fn forward_commands(&mut self) {
+        if let Some(commands) = self.input_layer_rx.take() {
+            let inputs = self.inputs_tx.clone();
+            thread::spawn(move || {
+                for command in commands {
+                    if inputs.send(SessionInput::Command(command)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Drives the session state machine until shutdown is requested,
+    /// reconnecting to the box whenever it goes away.
+    pub fn run(&mut self) {
+        self.forward_commands();
+        while !signal::shutdown_requested() {
+            match self.state {
+                SessionState::Searching => self.search(),
+                SessionState::Recovering => self.recover(),
+                _ => self.communicate(),
+            }
+            self.report_stats();
+            if signal::capture_toggle_requested() {
+                self.toggle_capture();
+            }
+        }
+        self.shutdown();
+    }
+
+    /// Tells the box we are leaving, waits for that to go out, then lets
+    /// go of the dongle.
+    fn shutdown(&mut self) {
+        let connected = !matches!(self.state, SessionState::Searching | SessionState::Recovering);
+        if let Some(writer) = self.writer.take() {
+            if connected {
+                println!("Closing the box");
+                writer.send(MsgType::CloseBox(CloseBox::new()));
+            }
+            writer.finish();
+        }
+        self.close();
+        self.capture.stop();
+        self.recorder.stop();
+    }
+
+    fn start_capture(&mut self, path: PathBuf) {
+        match self.capture.start(&path) {
+            Ok(()) => self.capture_path = Some(path),
+            Err(e) => println!("Could not capture to {}: {}", path.display(), e),
+        }
+    }
+
+    /// SIGUSR1 flips capturing on and off, to the last path captured to.
+    fn toggle_capture(&mut self) {
+        if self.capture.is_active() {
+            self.capture.stop();
+        } else if let Some(path) = self.capture_path.clone() {
+            self.start_capture(path);
+        } else {
+            println!("Not capturing: no capture file configured (--capture PATH)");
+        }
+    }
+
+    fn report_stats(&mut self) {
+        if self.last_stats.elapsed() >= STATS_INTERVAL {
+            self.last_stats = Instant::now();
+            let _ = self.player_layer_tx.send(LinkEvent::Stats(self.stats.snapshot()));
+        }
+    }
+
+    fn search(&mut self) {
+        match self.connector.connect() {
+            Ok(Some(transport)) => self.attach(transport),
+            Ok(None) => signal::sleep(SEARCH_INTERVAL),
+            Err(e) => {
+                println!("Could not connect to the box: {}", e);
+                self.transition(SessionEvent::TransferError);
+            }
+        }
+        self.drain_inputs();
+    }
+
+    /// Starts the reader and writer on a fresh transport and opens the box.
+    fn attach(&mut self, transport: Box<dyn Transport>) {
+        let transport: Arc<dyn Transport> = Arc::from(transport);
+        self.generation += 1;
+        if self.generation > 1 {
+            self.stats.reconnect();
+        }
+        let (generation, inputs) = (self.generation, self.inputs_tx.clone());
+        self.reader = Some(Reader::spawn(transport.clone(), self.pool.clone(), self.stats.clone(),
+                                         self.capture.clone(), self.recorder.clone(),
+                                         move |result| inputs.send(SessionInput::Read(generation, result)).is_ok()));
+        self.writer = Some(Writer::spawn(transport.clone(), self.stats.clone(), self.capture.clone(),
+                                         self.recorder.clone()));
+        self.transport = Some(transport);
+        self.transition(SessionEvent::DeviceFound);
+        self.opened_at = Instant::now();
         println!("Connected");
-        loop {
-            let heartbeat_packet = MsgType::Heartbeat(Heartbeat::new()); // Needs to be tested thoroughly before true implementation
-            self.tx_packet(heartbeat_packet).unwrap();
-            let rx_packet = self.rx_packet();
-            rx_packet.unwrap();
-            // This block can be used to send data across the modules
-            match self.input_layer_rx.try_recv() {
-                Ok(_) => continue,
-                Err(_e) => {},
+        if let Err(e) = self.start_box() {
+            self.fail(e);
+        }
+    }
+
+    fn recover(&mut self) {
+        self.close();
+        signal::sleep(self.backoff.next_delay());
+        self.drain_inputs();
+        self.transition(SessionEvent::BackoffElapsed);
+    }
+
+    fn fail(&mut self, e: TransportError) {
+        println!("Link error in {:?}: {}", self.state, e);
+        self.transition(SessionEvent::TransferError);
+    }
+
+    fn handle_packet(&mut self, msg: MsgType) {
+        // Anything at all from the box means it survived the startup sequence
+        self.transition(SessionEvent::BoxResponded);
+        match msg {
+            MsgType::DevPlug(_) => self.transition(SessionEvent::PhonePlugged),
+            MsgType::DevUnplug(_) => self.transition(SessionEvent::PhoneUnplugged),
+            MsgType::Video(_) => {
+                self.last_video = Instant::now();
+                self.transition(SessionEvent::VideoReceived);
             }
-            self.player_layer_tx.send(()).unwrap();
+            MsgType::Audio(ref audio) => {
+                if let Some(command) = audio.command() {
+                    self.microphone.handle(command, audio.decode_type());
+                }
+            }
+            _ => {},
+        }
+        let _ = self.player_layer_tx.send(LinkEvent::Message(msg));
+    }
+
+    fn check_timeouts(&mut self) {
+        match self.state {
+            SessionState::Opening if self.opened_at.elapsed() > OPEN_TIMEOUT => {
+                self.transition(SessionEvent::OpenTimeout);
+            }
+            SessionState::Streaming if self.last_video.elapsed() > STREAM_TIMEOUT => {
+                self.transition(SessionEvent::StreamTimeout);
+            }
+            _ => {},
+        }
+    }
 
-            thread::sleep(Duration::new(1,0));
+    fn transition(&mut self, event: SessionEvent) {
+        let next = self.state.next(event);
+        if next == self.state {
+            return;
+        }
+        println!("Session {:?} -> {:?} ({:?})", self.state, next, event);
+        self.state = next;
+        if next == SessionState::Idle {
+            self.backoff.reset();
         }
+        // The player going away shouldn't take the link down with it
+        let _ = self.player_layer_tx.send(LinkEvent::StateChanged(next));
     }
 }
 
-pub fn link_thread(tx: Sender<()>, rx: Receiver<()>) -> std::thread::JoinHandle<()> {
-    thread::spawn(move|| {
-        let mut link_layer = LinkLayer::new(tx, rx);
-        link_layer.start_box();
-        link_layer.communicate();
-    })
-}
\ No newline at end of file
+/// Sets up the link layer on the calling thread, so setup errors come back
+/// to the caller, then runs it on a thread of its own.
+pub fn link_thread(config: LinkConfig, tx: Sender<LinkEvent>, rx: Receiver<LinkCommand>) 
+                -> std::result::Result<std::thread::JoinHandle<()>, LinkError> {
+    let mut link_layer = LinkLayer::new(config, tx, rx)?;
+    Ok(thread::spawn(move|| {
+        link_layer.run();
+    }))
+}
@@ -8,8 +8,17 @@ use std::vec::Vec;
 extern crate serde;
 extern crate bincode;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 
-const PROTOCOL_MAGIC:u32 = 0x55aa55aa;
+use super::buffer_pool::Payload;
+
+pub const PROTOCOL_MAGIC:u32 = 0x55aa55aa;
+pub const BOX_MSG_HEADER_LEN: usize = 16;
+/// Larger than any message the box sends, header included; anything
+/// bigger is corruption.
+pub const MAX_MSG_LEN: usize = 16 * 1024 * 1024;
+const VIDEO_FIXED_LEN: usize = BOX_MSG_HEADER_LEN + 20;
+const AUDIO_FIXED_LEN: usize = BOX_MSG_HEADER_LEN + 12;
 const OPENBOX:u32 = 1;
 const DEVPLUG:u32 = 2;
 const DEVUNPLUG:u32 = 4;
@@ -20,11 +29,28 @@ const BUTTONCTL:u32 = 8;
 const BTADDR:u32 = 10;
 const BTPIN:u32 = 12;
 const MANINFO:u32 = 20;
+const CLOSEBOX:u32 = 21;
 const MULTITOUCH:u32 = 23; 
 const SENDFILE:u32 = 153;
 const HEARTBEAT:u32 = 170;
 const SWVER:u32 = 204;
 
+pub const PHONE_TYPE_CARPLAY: u32 = 3;
+pub const AUDIO_TYPE_MEDIA: u32 = 1;
+/// Audio type of microphone samples sent to the box.
+pub const AUDIO_TYPE_MICROPHONE: u32 = 3;
+
+pub const TOUCH_DOWN: u32 = 14;
+pub const TOUCH_MOVE: u32 = 15;
+pub const TOUCH_UP: u32 = 16;
+
+/// `ButtonCtl` command asking the phone for a fresh IDR frame.
+pub const BUTTON_REQUEST_KEYFRAME: u32 = 12;
+/// `ButtonCtl` commands choosing the microphone for Siri and calls: the
+/// car's, sent up as `Audio`, or the one on the box.
+pub const BUTTON_USE_CAR_MIC: u32 = 7;
+pub const BUTTON_USE_BOX_MIC: u32 = 15;
+
 #[derive(Serialize, Deserialize)]
 pub enum MsgType {
     OpenBox(OpenBox),
@@ -37,16 +63,63 @@ pub enum MsgType {
     BtAddr(BtAddr),
     BtPin(BtPin),
     ManInfo(ManInfo),
+    CloseBox(CloseBox),
     MultiTouch(MultiTouch),
     SendFile(SendFile),
     Heartbeat(Heartbeat),
     SwVer(SwVer),
 }
 
+impl MsgType {
+    /// The numeric type sent in the message header.
+    pub fn msg_type(&self) -> u32 {
+        match self {
+            MsgType::OpenBox(_) => OPENBOX,
+            MsgType::DevPlug(_) => DEVPLUG,
+            MsgType::DevUnplug(_) => DEVUNPLUG,
+            MsgType::Touch(_) => TOUCH,
+            MsgType::Video(_) => VIDEO,
+            MsgType::Audio(_) => AUDIO,
+            MsgType::ButtonCtl(_) => BUTTONCTL,
+            MsgType::BtAddr(_) => BTADDR,
+            MsgType::BtPin(_) => BTPIN,
+            MsgType::ManInfo(_) => MANINFO,
+            MsgType::CloseBox(_) => CLOSEBOX,
+            MsgType::MultiTouch(_) => MULTITOUCH,
+            MsgType::SendFile(_) => SENDFILE,
+            MsgType::Heartbeat(_) => HEARTBEAT,
+            MsgType::SwVer(_) => SWVER,
+        }
+    }
+}
+
+/// Human readable name of a message type number, for logs and statistics.
+pub fn msg_type_name(msg_type: u32) -> Option<&'static str> {
+    let name = match msg_type {
+        OPENBOX => "OpenBox",
+        DEVPLUG => "DevPlug",
+        DEVUNPLUG => "DevUnplug",
+        TOUCH => "Touch",
+        VIDEO => "Video",
+        AUDIO => "Audio",
+        BUTTONCTL => "ButtonCtl",
+        BTADDR => "BtAddr",
+        BTPIN => "BtPin",
+        MANINFO => "ManInfo",
+        CLOSEBOX => "CloseBox",
+        MULTITOUCH => "MultiTouch",
+        SENDFILE => "SendFile",
+        HEARTBEAT => "Heartbeat",
+        SWVER => "SwVer",
+        _ => return None,
+    };
+    Some(name)
+}
+
 pub trait BaseBoxMsg<'de> {
     // Serialization is little endian
     fn serialize(&self) -> Vec<u8>; 
-    fn deserialize(data_type: u32, data: &'de Vec<u8>) -> MsgType;
+    fn deserialize(data_type: u32, data: &'de [u8]) -> bincode::Result<MsgType>;
 }
 
 impl<'de> BaseBoxMsg<'de> for MsgType
@@ -56,74 +129,179 @@ where
         fn serialize(&self) -> Vec<u8> {
             let mut ret: Vec<u8> = bincode::serialize(self).unwrap();
             ret.drain(..4); // discard enum id, this could be handled more elegantly
+            // Raw payloads go on the wire as-is, bincode would length-prefix them
+            match self {
+                MsgType::Video(video) => ret.extend_from_slice(&video.data),
+                MsgType::Audio(audio) => ret.extend_from_slice(&audio.data),
+                _ => {},
+            }
             ret
         }
-        fn deserialize(data_type: u32, data: &'de Vec<u8>) -> MsgType {
-            match data_type {
+        fn deserialize(data_type: u32, data: &'de [u8]) -> bincode::Result<MsgType> {
+            let msg = match data_type {
                 // There should be a better way to do this; macros???
                 OPENBOX => {
-                    let inner: OpenBox = bincode::deserialize(data).unwrap();
+                    let inner: OpenBox = bincode::deserialize(data)?;
                     MsgType::OpenBox(inner)
                 }
                 DEVPLUG => {
-                    let inner: DevPlug = bincode::deserialize(data).unwrap();
+                    // Older firmware leaves out the wifi flag
+                    let inner: DevPlug = deserialize_padded(data, BOX_MSG_HEADER_LEN + 8)?;
                     MsgType::DevPlug(inner)
                 }
                 DEVUNPLUG => { 
-                    let inner: DevUnplug = bincode::deserialize(data).unwrap();
+                    let inner: DevUnplug = bincode::deserialize(data)?;
                     MsgType::DevUnplug(inner)
                 }
                 TOUCH => { 
-                    let inner: Touch = bincode::deserialize(data).unwrap();
+                    let inner: Touch = bincode::deserialize(data)?;
                     MsgType::Touch(inner)
                 }
                 VIDEO => { 
-                    let inner: Video = bincode::deserialize(data).unwrap();
+                    // The payload is raw H.264 and carries no length prefix
+                    let mut inner: Video = bincode::deserialize(data)?;
+                    inner.data = data.get(VIDEO_FIXED_LEN..).unwrap_or(&[]).to_vec().into();
                     MsgType::Video(inner)
                 }
                 AUDIO => { 
-                    let inner: Audio = bincode::deserialize(data).unwrap();
+                    let mut inner: Audio = bincode::deserialize(data)?;
+                    inner.data = data.get(AUDIO_FIXED_LEN..).unwrap_or(&[]).to_vec().into();
                     MsgType::Audio(inner)
                 }
                 BUTTONCTL => { 
-                    let inner: ButtonCtl = bincode::deserialize(data).unwrap();
+                    let inner: ButtonCtl = bincode::deserialize(data)?;
                     MsgType::ButtonCtl(inner)
                 }
                 BTADDR => { 
-                    let inner: BtAddr = bincode::deserialize(data).unwrap();
+                    let inner: BtAddr = deserialize_padded(data, BOX_MSG_HEADER_LEN + 17)?;
                     MsgType::BtAddr(inner)
                 }
                 BTPIN => { 
-                    let inner: BtPin = bincode::deserialize(data).unwrap();
+                    let inner: BtPin = bincode::deserialize(data)?;
                     MsgType::BtPin(inner)
                 }
                 MANINFO => { 
-                    let inner: ManInfo = bincode::deserialize(data).unwrap();
+                    let inner: ManInfo = bincode::deserialize(data)?;
                     MsgType::ManInfo(inner)
                 }
+                CLOSEBOX => { 
+                    let inner: CloseBox = bincode::deserialize(data)?;
+                    MsgType::CloseBox(inner)
+                }
                 MULTITOUCH => { 
-                    let inner: MultiTouch = bincode::deserialize(data).unwrap();
+                    let inner: MultiTouch = bincode::deserialize(data)?;
                     MsgType::MultiTouch(inner)
                 }
                 SENDFILE => { 
-                    let inner: SendFile = bincode::deserialize(data).unwrap();
+                    let inner: SendFile = bincode::deserialize(data)?;
                     MsgType::SendFile(inner)
                 }
                 HEARTBEAT => { 
-                    let inner: Heartbeat = bincode::deserialize(data).unwrap();
+                    let inner: Heartbeat = bincode::deserialize(data)?;
                     MsgType::Heartbeat(inner)
                 }
                 SWVER => { 
-                    let inner: SwVer = bincode::deserialize(data).unwrap();
+                    let inner: SwVer = deserialize_padded(data, BOX_MSG_HEADER_LEN + 32)?;
                     MsgType::SwVer(inner)
                 }
                 _ => {
-                    let inner: Heartbeat = bincode::deserialize(data).unwrap();
+                    let inner: Heartbeat = bincode::deserialize(data)?;
                     MsgType::Heartbeat(inner)
                 }
+            };
+            Ok(msg)
+        }
+    }
+
+impl MsgType {
+    /// Like `deserialize`, but `Video` and `Audio` keep a slice of `frame`
+    /// as their data instead of copying it out.
+    pub fn from_frame(data_type: u32, frame: &Payload) -> bincode::Result<MsgType> {
+        match data_type {
+            VIDEO => {
+                let mut inner: Video = bincode::deserialize(frame)?;
+                inner.data = frame.slice(VIDEO_FIXED_LEN..);
+                Ok(MsgType::Video(inner))
             }
+            AUDIO => {
+                let mut inner: Audio = bincode::deserialize(frame)?;
+                inner.data = frame.slice(AUDIO_FIXED_LEN..);
+                Ok(MsgType::Audio(inner))
+            }
+            _ => <MsgType as BaseBoxMsg>::deserialize(data_type, frame),
         }
     }
+}
+
+/// Decodes a message whose trailing fields may be missing on the wire;
+/// anything absent reads as zero.
+fn deserialize_padded<T: DeserializeOwned>(data: &[u8], len: usize) -> bincode::Result<T> {
+    if data.len() >= len {
+        return bincode::deserialize(data);
+    }
+    let mut padded = data.to_vec();
+    padded.resize(len, 0);
+    bincode::deserialize(&padded)
+}
+
+/// Commands carried in place of samples by short `Audio` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCommand {
+    OutputStart = 1,
+    OutputStop = 2,
+    InputConfig = 3,
+    PhoneCallStart = 4,
+    PhoneCallStop = 5,
+    NaviStart = 6,
+    NaviStop = 7,
+    SiriStart = 8,
+    SiriStop = 9,
+    MediaStart = 10,
+    MediaStop = 11,
+    AlertStart = 12,
+    AlertStop = 13,
+}
+
+impl AudioCommand {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        use AudioCommand::*;
+        [OutputStart, OutputStop, InputConfig, PhoneCallStart, PhoneCallStop,
+         NaviStart, NaviStop, SiriStart, SiriStop, MediaStart, MediaStop,
+         AlertStart, AlertStop].iter().copied().find(|command| *command as u8 == value)
+    }
+}
+
+/// Sample rate and channel count of an `Audio` decode type; the samples are
+/// always signed 16 bit little endian.
+pub fn audio_format(decode_type: u32) -> Option<(u32, u16)> {
+    match decode_type {
+        1 | 2 => Some((44100, 2)),
+        3 => Some((8000, 1)),
+        4 => Some((48000, 2)),
+        5 => Some((16000, 1)),
+        6 => Some((24000, 1)),
+        7 => Some((16000, 2)),
+        _ => None,
+    }
+}
+
+/// Inverse of `audio_format`.
+pub fn decode_type_for(sample_rate: u32, channels: u16) -> Option<u32> {
+    (1..=7).find(|&decode_type| audio_format(decode_type) == Some((sample_rate, channels)))
+}
+
+/// Copies `text` into a NUL padded fixed size field.
+fn fixed_str<const N: usize>(text: &str) -> [u8; N] {
+    let mut field = [0; N];
+    let len = std::cmp::min(text.len(), N);
+    field[..len].copy_from_slice(&text.as_bytes()[..len]);
+    field
+}
+
+fn from_fixed_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct BoxMsgHeader {
@@ -139,9 +317,12 @@ impl BoxMsgHeader {
             magic: PROTOCOL_MAGIC,
             msg_len,
             msg_type,
-            msg_parity: (msg_type as i32 ^ -1) as u32 & 0xffffffff
+            msg_parity: (msg_type as i32 ^ -1) as u32
         }
     }
+    pub fn is_valid(&self) -> bool {
+        (self.magic == PROTOCOL_MAGIC) & (self.msg_parity == !self.msg_type)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -159,6 +340,8 @@ pub struct OpenBox {
 #[derive(Serialize, Deserialize)]
 pub struct DevPlug{
     header: BoxMsgHeader,
+    phone_type: u32,
+    wifi: u32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -169,27 +352,44 @@ pub struct DevUnplug{
 #[derive(Serialize, Deserialize)]
 pub struct Touch{
     header: BoxMsgHeader,
+    action: u32,
+    x: u32, // 0..10000 across the screen
+    y: u32,
+    flags: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Video{
     header: BoxMsgHeader,
-    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    flags: u32,
+    length: u32,
+    unknown: u32,
+    #[serde(skip)]
+    data: Payload, // H.264 elementary stream
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Audio{
     header: BoxMsgHeader,
+    decode_type: u32,
+    volume: f32,
+    audio_type: u32,
+    #[serde(skip)]
+    data: Payload, // Samples, or a single `AudioCommand` byte
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ButtonCtl{
     header: BoxMsgHeader,
+    command: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct BtAddr{
     header: BoxMsgHeader,
+    addr: [u8; 17],
 }
 
 #[derive(Serialize, Deserialize)]
@@ -204,6 +404,12 @@ pub struct ManInfo{
     model: i32,
 }
 
+/// Tells the box the client is going away, so it stops the phone session.
+#[derive(Serialize, Deserialize)]
+pub struct CloseBox{
+    header: BoxMsgHeader,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct MultiTouch{
     header: BoxMsgHeader,
@@ -222,6 +428,7 @@ pub struct Heartbeat {
 #[derive(Serialize, Deserialize)]
 pub struct SwVer{
     header: BoxMsgHeader,
+    version: [u8; 32],
 }
 
 impl OpenBox {
@@ -237,14 +444,28 @@ impl OpenBox {
             phone_work_mode: 2
         }
     }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn framerate(&self) -> u32 {
+        self.framerate
+    }
 }
 
 impl DevPlug {
-    pub fn new() -> Self {
+    pub fn new(phone_type: u32) -> Self {
         Self {
-            header: BoxMsgHeader::new(DEVPLUG, 0), 
+            header: BoxMsgHeader::new(DEVPLUG, 8), 
+            phone_type,
+            wifi: 0,
         }
     }
+    pub fn phone_type(&self) -> u32 {
+        self.phone_type
+    }
 }
 
 impl DevUnplug {
@@ -256,44 +477,117 @@ impl DevUnplug {
 }
 
 impl Touch {
-    pub fn new() -> Self {
+    pub fn new(action: u32, x: u32, y: u32) -> Self {
         Self {
-            header: BoxMsgHeader::new(TOUCH, 0), 
+            header: BoxMsgHeader::new(TOUCH, 16), 
+            action,
+            x,
+            y,
+            flags: 0,
         }
     }
+    pub fn action(&self) -> u32 {
+        self.action
+    }
+    pub fn position(&self) -> (u32, u32) {
+        (self.x, self.y)
+    }
 }
 
 impl Video {
-    pub fn new(data: Vec<u8>) -> Self {
+    pub fn new<P: Into<Payload>>(width: u32, height: u32, data: P) -> Self {
+        let data = data.into();
         Self {
-            header: BoxMsgHeader::new(VIDEO, 0), 
+            header: BoxMsgHeader::new(VIDEO, (VIDEO_FIXED_LEN - BOX_MSG_HEADER_LEN + data.len()) as u32), 
+            width,
+            height,
+            flags: 0,
+            length: data.len() as u32,
+            unknown: 0,
             data
         }
     }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// The data as a shared handle that can outlive the message.
+    pub fn payload(&self) -> Payload {
+        self.data.clone()
+    }
 }
 
 impl Audio {
-    pub fn new() -> Self {
+    pub fn new<P: Into<Payload>>(decode_type: u32, audio_type: u32, data: P) -> Self {
+        let data = data.into();
         Self {
-            header: BoxMsgHeader::new(AUDIO, 0), 
+            header: BoxMsgHeader::new(AUDIO, (AUDIO_FIXED_LEN - BOX_MSG_HEADER_LEN + data.len()) as u32), 
+            decode_type,
+            volume: 0.0,
+            audio_type,
+            data,
+        }
+    }
+    pub fn with_command(decode_type: u32, audio_type: u32, command: AudioCommand) -> Self {
+        Audio::new(decode_type, audio_type, vec![command as u8])
+    }
+    pub fn decode_type(&self) -> u32 {
+        self.decode_type
+    }
+    pub fn audio_type(&self) -> u32 {
+        self.audio_type
+    }
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+    /// The command this message carries, if it isn't a block of samples.
+    pub fn command(&self) -> Option<AudioCommand> {
+        match self.data.len() {
+            1 => AudioCommand::from_u8(self.data[0]),
+            _ => None,
         }
     }
+    /// The samples this message carries, if any. A four byte payload is a
+    /// volume ramp duration rather than audio.
+    pub fn samples(&self) -> Option<&[u8]> {
+        match self.data.len() {
+            0 | 1 | 4 => None,
+            _ => Some(&self.data),
+        }
+    }
+    /// The data as a shared handle that can outlive the message.
+    pub fn payload(&self) -> Payload {
+        self.data.clone()
+    }
 }
 
 impl ButtonCtl {
-    pub fn new() -> Self {
+    pub fn new(command: u32) -> Self {
         Self {
-            header: BoxMsgHeader::new(BUTTONCTL, 0), 
+            header: BoxMsgHeader::new(BUTTONCTL, 4), 
+            command,
         }
     }
+    pub fn command(&self) -> u32 {
+        self.command
+    }
 }
 
 impl BtAddr {
-    pub fn new() -> Self {
+    pub fn new(addr: &str) -> Self {
         Self {
-            header: BoxMsgHeader::new(BTADDR, 0), 
+            header: BoxMsgHeader::new(BTADDR, 17), 
+            addr: fixed_str(addr),
         }
     }
+    pub fn addr(&self) -> String {
+        from_fixed_str(&self.addr)
+    }
 }
 
 impl BtPin {
@@ -307,7 +601,7 @@ impl BtPin {
 impl ManInfo {
     pub fn new(brand: i32, model: i32) -> Self {
         Self {
-            header: BoxMsgHeader::new(MANINFO, 2), // Should be length of payload
+            header: BoxMsgHeader::new(MANINFO, 8),
             brand, 
             model,
         }
@@ -330,6 +624,14 @@ impl SendFile {
     }
 }
 
+impl CloseBox {
+    pub fn new() -> Self {
+        Self {
+            header: BoxMsgHeader::new(CLOSEBOX, 0), 
+        }
+    }
+}
+
 impl Heartbeat {
     pub fn new() -> Self {
         Self {
@@ -339,9 +641,26 @@ impl Heartbeat {
 }
 
 impl SwVer {
-    pub fn new() -> Self {
+    pub fn new(version: &str) -> Self {
         Self {
-            header: BoxMsgHeader::new(SWVER, 0), 
+            header: BoxMsgHeader::new(SWVER, 32), 
+            version: fixed_str(version),
         }
     }
-}
\ No newline at end of file
+    pub fn version(&self) -> String {
+        from_fixed_str(&self.version)
+    }
+}
+
+// Messages without a payload have nothing to configure
+macro_rules! default_from_new {
+    ($($msg:ident),*) => {
+        $(impl Default for $msg {
+            fn default() -> Self {
+                Self::new()
+            }
+        })*
+    };
+}
+
+default_from_new!(DevUnplug, BtPin, CloseBox, MultiTouch, SendFile, Heartbeat);
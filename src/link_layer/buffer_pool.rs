@@ -0,0 +1,192 @@
+//! # Buffer Pool for CarPlay Client
+//!
+//! Receive buffers are recycled instead of allocated per message. A frame
+//! is read into a `PooledBuf`, frozen into a reference counted `Payload`,
+//! and `Video`/`Audio` messages keep a slice of that same buffer as their
+//! data. Once the last slice is dropped, wherever that happens, the buffer
+//! goes back to the pool. A frame is copied once, out of the transport into
+//! its buffer; from there on it is only shared.
+
+use std::fmt;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Room for the largest packet we accept (see `OpenBox`) plus its header.
+pub const DEFAULT_BUFFER_SIZE: usize = 49152 + 16;
+/// Buffers kept around once returned; anything beyond is freed.
+pub const DEFAULT_MAX_FREE: usize = 32;
+
+/// How the pool has been keeping up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers handed out from the free list.
+    pub reused: u64,
+    /// Times the free list was empty and a buffer had to be allocated.
+    pub exhausted: u64,
+    /// Buffers handed out and not yet returned.
+    pub in_use: usize,
+    /// Most buffers ever in use at once.
+    pub peak_in_use: usize,
+    /// Buffers waiting on the free list.
+    pub free: usize,
+}
+
+struct Inner {
+    free: Vec<Vec<u8>>,
+    stats: PoolStats,
+}
+
+struct Shared {
+    buffer_size: usize,
+    max_free: usize,
+    inner: Mutex<Inner>,
+}
+
+/// Cheap to clone; all clones share one free list.
+#[derive(Clone)]
+pub struct BufferPool {
+    shared: Arc<Shared>,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_free: usize) -> Self {
+        let inner = Inner { free: Vec::new(), stats: PoolStats::default() };
+        Self { shared: Arc::new(Shared { buffer_size, max_free, inner: Mutex::new(inner) }) }
+    }
+
+    /// Takes a buffer of `len` zeroed bytes.
+    pub fn get(&self, len: usize) -> PooledBuf {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let mut data = match inner.free.pop() {
+            Some(data) => {
+                inner.stats.reused += 1;
+                data
+            }
+            None => {
+                inner.stats.exhausted += 1;
+                Vec::with_capacity(self.shared.buffer_size)
+            }
+        };
+        inner.stats.in_use += 1;
+        inner.stats.peak_in_use = inner.stats.peak_in_use.max(inner.stats.in_use);
+        drop(inner);
+        data.clear();
+        data.resize(len, 0);
+        PooledBuf { data, pool: Arc::downgrade(&self.shared) }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let inner = self.shared.inner.lock().unwrap();
+        PoolStats { free: inner.free.len(), ..inner.stats }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(DEFAULT_BUFFER_SIZE, DEFAULT_MAX_FREE)
+    }
+}
+
+/// A writable buffer on loan from a `BufferPool`.
+pub struct PooledBuf {
+    data: Vec<u8>,
+    pool: Weak<Shared>,
+}
+
+impl PooledBuf {
+    /// Grows or shrinks the buffer, zero filling any new bytes.
+    pub fn resize(&mut self, len: usize) {
+        self.data.resize(len, 0);
+    }
+    /// Makes the buffer read only and shareable.
+    pub fn freeze(self) -> Payload {
+        let end = self.data.len();
+        Payload { buf: Arc::new(self), start: 0, end }
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        // The pool may be gone already, then the buffer is simply freed
+        if let Some(shared) = self.pool.upgrade() {
+            let mut inner = shared.inner.lock().unwrap();
+            inner.stats.in_use -= 1;
+            if inner.free.len() < shared.max_free {
+                inner.free.push(std::mem::take(&mut self.data));
+            }
+        }
+    }
+}
+
+/// A shared, read only slice of a received frame. Cloning and slicing
+/// only bump a reference count.
+#[derive(Clone)]
+pub struct Payload {
+    buf: Arc<PooledBuf>,
+    start: usize,
+    end: usize,
+}
+
+impl Payload {
+    /// A payload sharing this one's buffer; `range` is relative to it and
+    /// clamped to its length.
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> Payload {
+        let len = self.len();
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        }.min(len);
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => len,
+        }.clamp(start, len);
+        Payload { buf: self.buf.clone(), start: self.start + start, end: self.start + end }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Wraps data that didn't come from a pool, e.g. messages built to send.
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Self {
+        PooledBuf { data, pool: Weak::new() }.freeze()
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::from(Vec::new())
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Payload({} bytes)", self.len())
+    }
+}
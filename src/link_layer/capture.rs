@@ -0,0 +1,274 @@
+//! # Traffic Capture for CarPlay Client
+//!
+//! Writes every frame exchanged with the box to a pcapng file, in the Linux
+//! usbmon format (`LINKTYPE_USB_LINUX_MMAPPED`), so captures open in
+//! Wireshark next to ones taken from a head unit with `usbmon`. Each frame
+//! is recorded as a single bulk transfer on endpoint 0x81 (from the box) or
+//! 0x01 (to the box), whatever transport it actually went over.
+//!
+//! Capturing can be started and stopped while the link is running. Once a
+//! file reaches its size limit the capture moves on to a new file:
+//! `link.pcapng`, then `link-1.pcapng`, `link-2.pcapng` and so on. Files
+//! that already exist are skipped, so capturing again to the same path
+//! carries on the numbering instead of overwriting earlier captures.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPTION_END: u16 = 0;
+const OPTION_SHB_USERAPPL: u16 = 4;
+const OPTION_IF_NAME: u16 = 2;
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+
+const USBMON_SUBMIT: u8 = b'S';
+const USBMON_COMPLETE: u8 = b'C';
+const USBMON_BULK: u8 = 3;
+/// Setup packet flag for anything but a control transfer.
+const USBMON_NO_SETUP: u8 = b'-';
+const EP_IN: u8 = 0x81;
+const EP_OUT: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the box.
+    In,
+    /// To the box.
+    Out,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    /// Where to capture to; capturing starts with the link when set.
+    pub path: Option<PathBuf>,
+    /// Size after which a new file is started; 0 never rotates.
+    pub max_file_size: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self { path: None, max_file_size: DEFAULT_MAX_FILE_SIZE }
+    }
+}
+
+/// Cheap to clone; all clones write to the same capture.
+#[derive(Clone)]
+pub struct Capture {
+    max_file_size: u64,
+    file: Arc<Mutex<Option<CaptureFile>>>,
+}
+
+impl Capture {
+    pub fn new(max_file_size: u64) -> Self {
+        Self { max_file_size, file: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Starts capturing to `path`, ending any capture already running.
+    pub fn start(&self, path: &Path) -> io::Result<()> {
+        let file = CaptureFile::create(path.to_path_buf(), unused(path, 0))?;
+        println!("Capturing link traffic to {}", numbered(path, file.index).display());
+        if let Some(previous) = self.file.lock().unwrap().replace(file) {
+            previous.finish();
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if let Some(file) = self.file.lock().unwrap().take() {
+            println!("Stopped capturing to {}", file.base.display());
+            file.finish();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    /// Appends one frame, header included. A capture that fails to write
+    /// is stopped; the link itself carries on.
+    pub fn record(&self, direction: Direction, frame: &[u8]) {
+        let mut file = self.file.lock().unwrap();
+        let result = match file.as_mut() {
+            Some(capture) => capture.record(direction, frame, self.max_file_size),
+            None => return,
+        };
+        if let Err(e) = result {
+            println!("Capture stopped: {}", e);
+            *file = None;
+        }
+    }
+}
+
+struct CaptureFile {
+    /// Path of the first file; later ones are numbered after it.
+    base: PathBuf,
+    index: u32,
+    out: BufWriter<File>,
+    written: u64,
+    /// usbmon tags each submission with an id; any unique number will do.
+    next_id: u64,
+}
+
+impl CaptureFile {
+    fn create(base: PathBuf, index: u32) -> io::Result<Self> {
+        let out = BufWriter::new(File::create(numbered(&base, index))?);
+        let mut file = Self { base, index, out, written: 0, next_id: 1 };
+        file.write_headers()?;
+        Ok(file)
+    }
+
+    fn write_headers(&mut self) -> io::Result<()> {
+        let mut shb = Vec::new();
+        put_u32(&mut shb, BYTE_ORDER_MAGIC);
+        put_u16(&mut shb, 1); // version 1.0
+        put_u16(&mut shb, 0);
+        shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+        put_option(&mut shb, OPTION_SHB_USERAPPL, concat!("carplay-client ", env!("CARGO_PKG_VERSION")).as_bytes());
+        put_u32(&mut shb, OPTION_END as u32);
+        self.write_block(BLOCK_SECTION_HEADER, &shb)?;
+
+        let mut idb = Vec::new();
+        put_u16(&mut idb, LINKTYPE_USB_LINUX_MMAPPED);
+        put_u16(&mut idb, 0);
+        put_u32(&mut idb, 0); // no snap length
+        put_option(&mut idb, OPTION_IF_NAME, b"carplay");
+        put_u32(&mut idb, OPTION_END as u32);
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION, &idb)
+    }
+
+    fn record(&mut self, direction: Direction, frame: &[u8], max_file_size: u64) -> io::Result<()> {
+        if max_file_size > 0 && self.written >= max_file_size {
+            self.rotate()?;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // Data travels with the submission going out and with the
+        // completion coming in, which is where usbmon puts it too
+        let (event, endpoint) = match direction {
+            Direction::In => (USBMON_COMPLETE, EP_IN),
+            Direction::Out => (USBMON_SUBMIT, EP_OUT),
+        };
+        let mut epb = Vec::with_capacity(20 + 64 + frame.len() + 3);
+        put_u32(&mut epb, 0); // interface
+        let micros = now.as_micros() as u64;
+        put_u32(&mut epb, (micros >> 32) as u32);
+        put_u32(&mut epb, micros as u32);
+        put_u32(&mut epb, (64 + frame.len()) as u32);
+        put_u32(&mut epb, (64 + frame.len()) as u32);
+
+        epb.extend_from_slice(&id.to_le_bytes());
+        epb.extend_from_slice(&[event, USBMON_BULK, endpoint, 0]); // device number unknown
+        put_u16(&mut epb, 0); // bus number unknown
+        epb.extend_from_slice(&[USBMON_NO_SETUP, 0]); // data present
+        epb.extend_from_slice(&(now.as_secs() as i64).to_le_bytes());
+        epb.extend_from_slice(&(now.subsec_micros() as i32).to_le_bytes());
+        epb.extend_from_slice(&0i32.to_le_bytes()); // status
+        put_u32(&mut epb, frame.len() as u32);
+        put_u32(&mut epb, frame.len() as u32);
+        epb.extend_from_slice(&[0; 8]); // setup packet
+        epb.extend_from_slice(&[0; 16]); // interval, start frame, flags, descriptors
+        epb.extend_from_slice(frame);
+        pad(&mut epb);
+        self.write_block(BLOCK_ENHANCED_PACKET, &epb)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        let next = CaptureFile::create(self.base.clone(), unused(&self.base, self.index + 1))?;
+        println!("Capture continues in {}", numbered(&next.base, next.index).display());
+        *self = next;
+        Ok(())
+    }
+
+    /// `body` must already be padded to a multiple of four bytes.
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let len = (12 + body.len()) as u32;
+        self.out.write_all(&block_type.to_le_bytes())?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(body)?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.written += len as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) {
+        if let Err(e) = self.out.flush() {
+            println!("Could not finish capture {}: {}", self.base.display(), e);
+        }
+    }
+}
+
+/// `link.pcapng` for the first file, `link-1.pcapng` for the next one.
+fn numbered(base: &Path, index: u32) -> PathBuf {
+    if index == 0 {
+        return base.to_path_buf();
+    }
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match base.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, index),
+    };
+    base.with_file_name(name)
+}
+
+/// The first index from `from` on that names no existing file.
+fn unused(base: &Path, from: u32) -> u32 {
+    (from..).find(|&index| !numbered(base, index).exists()).unwrap_or(from)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    put_u16(buf, code);
+    put_u16(buf, value.len() as u16);
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    let padded = (buf.len() + 3) & !3;
+    buf.resize(padded, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn restarting_keeps_earlier_captures() {
+        let dir = std::env::temp_dir().join(format!("carplay-capture-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("link.pcapng");
+        let capture = Capture::new(0);
+        capture.start(&base).unwrap();
+        capture.record(Direction::In, &[1, 2, 3, 4]);
+        capture.stop();
+        let first = fs::metadata(&base).unwrap().len();
+
+        capture.start(&base).unwrap();
+        capture.stop();
+        let sizes: Vec<_> = ["link.pcapng", "link-1.pcapng"].iter()
+                                .map(|name| fs::metadata(dir.join(name)).map(|meta| meta.len()).ok())
+                                .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(sizes[0], Some(first));
+        assert!(sizes[1].unwrap() < first);
+    }
+}
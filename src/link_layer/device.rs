@@ -0,0 +1,144 @@
+//! # USB Device Selection for CarPlay Client
+//!
+//! Decides which attached USB device is the AutoBox Server hardware. Several
+//! vendors ship the same firmware under different IDs, and a bench may have
+//! more than one dongle attached, so matching is driven by a `DeviceFilter`
+//! rather than a single hard-coded VID/PID.
+
+use std::time::Duration;
+
+use rusb::*;
+
+/// VID/PID pairs known to speak the AutoBox protocol.
+pub const KNOWN_DEVICES: &[(u16, u16)] = &[
+    (0x1314, 0x1520),
+    (0x1314, 0x1521),
+];
+
+const STRING_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub struct DeviceFilter {
+    /// Accepted (vendor, product) pairs.
+    pub ids: Vec<(u16, u16)>,
+    /// Only accept a device on this bus number.
+    pub bus: Option<u8>,
+    /// Only accept a device behind this chain of hub ports, e.g. `[4, 2]`.
+    pub port_path: Option<Vec<u8>>,
+    /// Only accept a device reporting this serial number string.
+    pub serial: Option<String>,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self {
+            ids: KNOWN_DEVICES.to_vec(),
+            bus: None,
+            port_path: None,
+            serial: None,
+        }
+    }
+}
+
+impl DeviceFilter {
+    fn matches_id(&self, device_desc: &DeviceDescriptor) -> bool {
+        self.ids.iter().any(|&(vendor, product)| {
+            (device_desc.vendor_id() == vendor) & (device_desc.product_id() == product)
+        })
+    }
+
+    /// Devices that can't be queried are taken not to match, so one
+    /// misbehaving device doesn't stop the others from being found.
+    fn matches<T: UsbContext>(&self, device: &Device<T>) -> bool {
+        let device_desc = match device.device_descriptor() {
+            Ok(device_desc) => device_desc,
+            Err(_) => return false,
+        };
+        if !self.matches_id(&device_desc) {
+            return false;
+        }
+        if let Some(bus) = self.bus {
+            if device.bus_number() != bus {
+                return false;
+            }
+        }
+        if let Some(ref port_path) = self.port_path {
+            if device.port_numbers().as_ref() != Ok(port_path) {
+                return false;
+            }
+        }
+        if let Some(ref serial) = self.serial {
+            // Reading the serial needs the device open; it doesn't claim it
+            if read_serial(device, &device_desc).as_ref() != Some(serial) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns every attached device accepted by this filter.
+    pub fn find_all<T: UsbContext>(&self, usb_ctx: &T) -> Result<Vec<Device<T>>> {
+        Ok(usb_ctx.devices()?.iter().filter(|device| self.matches(device)).collect())
+    }
+
+    /// Returns the device to use, if any. When several devices match, the
+    /// first one wins and the others are listed so the user can narrow the
+    /// filter down. `listed` holds the bus and address of the devices that
+    /// matched last time; the list is only printed again once that
+    /// changes, so polling the bus doesn't repeat it.
+    pub fn find<T: UsbContext>(&self, usb_ctx: &T, listed: &mut Vec<(u8, u8)>) -> Result<Option<Device<T>>> {
+        let mut found = self.find_all(usb_ctx)?;
+        let locations: Vec<_> = found.iter().map(|device| (device.bus_number(), device.address())).collect();
+        if found.len() > 1 && *listed != locations {
+            println!("{} matching devices attached, using the first:", found.len());
+            for device in &found {
+                println!("  {}", describe(device));
+            }
+        }
+        *listed = locations;
+        Ok(if found.is_empty() { None } else { Some(found.remove(0)) })
+    }
+}
+
+fn read_serial<T: UsbContext>(device: &Device<T>, device_desc: &DeviceDescriptor) -> Option<String> {
+    let device_handle = device.open().ok()?;
+    let language = *device_handle.read_languages(STRING_TIMEOUT).ok()?.first()?;
+    device_handle.read_serial_number_string(language, device_desc, STRING_TIMEOUT).ok()
+}
+
+/// One-line summary of a device, using the same notation the filter accepts.
+pub fn describe<T: UsbContext>(device: &Device<T>) -> String {
+    let (vendor, product) = match device.device_descriptor() {
+        Ok(device_desc) => (device_desc.vendor_id(), device_desc.product_id()),
+        Err(_) => (0, 0),
+    };
+    let ports = device.port_numbers()
+                      .map(|ports| format_port_path(&ports))
+                      .unwrap_or_else(|_| "?".to_string());
+    let serial = device.device_descriptor().ok()
+                       .and_then(|device_desc| read_serial(device, &device_desc))
+                       .unwrap_or_else(|| "?".to_string());
+    format!("{:04x}:{:04x} bus {} port {} serial {}",
+            vendor, product, device.bus_number(), ports, serial)
+}
+
+/// Parses a `vid:pid` pair written in hex, e.g. `1314:1520`.
+pub fn parse_id(id: &str) -> std::result::Result<(u16, u16), String> {
+    let mut parts = id.splitn(2, ':');
+    let vendor = parts.next().unwrap_or("");
+    let product = parts.next().ok_or(format!("expected vid:pid, got `{}`", id))?;
+    let parse = |part: &str| u16::from_str_radix(part.trim_start_matches("0x"), 16)
+                                 .map_err(|_| format!("invalid USB id `{}`", id));
+    Ok((parse(vendor)?, parse(product)?))
+}
+
+/// Parses a dotted hub port chain, e.g. `4.2`.
+pub fn parse_port_path(path: &str) -> std::result::Result<Vec<u8>, String> {
+    path.split('.')
+        .map(|port| port.parse::<u8>().map_err(|_| format!("invalid port path `{}`", path)))
+        .collect()
+}
+
+pub fn format_port_path(ports: &[u8]) -> String {
+    ports.iter().map(|port| port.to_string()).collect::<Vec<_>>().join(".")
+}
@@ -0,0 +1,96 @@
+//! # Link Errors for CarPlay Client
+//!
+//! Everything that can go wrong while finding and setting up the box, as
+//! opposed to `TransportError`, which covers moving bytes once it is up.
+//! The messages are meant to be shown to whoever is installing the client.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+extern crate rusb;
+
+#[derive(Debug)]
+pub enum LinkError {
+    /// No attached device is accepted by the `DeviceFilter`.
+    DeviceNotFound,
+    /// The device node exists but this user may not open it.
+    PermissionDenied { vendor_id: u16, product_id: u16 },
+    /// No interface offers a bulk IN and OUT endpoint to talk through.
+    /// `layout` lists every endpoint the device does have.
+    NoBulkEndpoints { layout: Vec<String> },
+    /// Another driver or process holds the interface.
+    ClaimFailed { interface: u8, source: rusb::Error },
+    /// libusb itself could not be set up.
+    UsbInit(rusb::Error),
+    /// Any other USB failure during setup.
+    Usb(rusb::Error),
+    /// Connecting to an emulator or relay failed.
+    Io(io::Error),
+    /// The capture file given on the command line could not be created.
+    Capture { path: PathBuf, source: io::Error },
+    /// A session recording could not be created or read.
+    Recording { path: PathBuf, source: io::Error },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::DeviceNotFound => write!(f, "no matching dongle is plugged in"),
+            LinkError::PermissionDenied { vendor_id, product_id } => write!(f,
+                "permission denied opening the dongle ({:04x}:{:04x}). Allow access with a udev rule, e.g. \
+                 in /etc/udev/rules.d/52-carplay.rules:\n\n    \
+                 SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", ATTR{{idProduct}}==\"{:04x}\", \
+                 MODE=\"0660\", TAG+=\"uaccess\"\n\n\
+                 then run `udevadm control --reload` and replug the dongle",
+                vendor_id, product_id, vendor_id, product_id),
+            LinkError::NoBulkEndpoints { layout } => {
+                write!(f, "the device has no bulk IN/OUT endpoint pair with a valid packet size; \
+                           is it really a CarPlay dongle? Its endpoints are:")?;
+                if layout.is_empty() {
+                    write!(f, " none")?;
+                }
+                for endpoint in layout {
+                    write!(f, "\n    {}", endpoint)?;
+                }
+                Ok(())
+            }
+            LinkError::ClaimFailed { interface, source: rusb::Error::Busy } => write!(f,
+                "USB interface {} is in use by another program; is another carplay-client running?",
+                interface),
+            LinkError::ClaimFailed { interface, source } => write!(f,
+                "could not claim USB interface {}: {}", interface, source),
+            LinkError::UsbInit(e) => write!(f, "could not initialise libusb: {}", e),
+            LinkError::Usb(e) => write!(f, "USB error: {}", e),
+            LinkError::Io(e) => write!(f, "could not reach the box: {}", e),
+            LinkError::Capture { path, source } => write!(f,
+                "could not create capture file {}: {}", path.display(), source),
+            LinkError::Recording { path, source } => write!(f,
+                "could not open session recording {}: {}", path.display(), source),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LinkError::ClaimFailed { source, .. } => Some(source),
+            LinkError::UsbInit(e) | LinkError::Usb(e) => Some(e),
+            LinkError::Io(e) | LinkError::Capture { source: e, .. } |
+            LinkError::Recording { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusb::Error> for LinkError {
+    fn from(e: rusb::Error) -> Self {
+        LinkError::Usb(e)
+    }
+}
+
+impl From<io::Error> for LinkError {
+    fn from(e: io::Error) -> Self {
+        LinkError::Io(e)
+    }
+}
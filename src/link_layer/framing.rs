@@ -0,0 +1,215 @@
+//! # Message Framing for CarPlay Client
+//!
+//! Splits the byte stream coming out of a `Transport` into box protocol
+//! messages, and writes messages back out, independent of what the
+//! transport actually is.
+
+use std::time::Duration;
+
+use super::box_protocol::*;
+use super::buffer_pool::{BufferPool, Payload};
+use super::transport::{Result, Transport, TransportError};
+
+/// Once the first byte of a message has arrived, the rest of it has to
+/// follow within this long or the stream is considered out of sync.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn read_exact(transport: &dyn Transport, buf: &mut [u8], timeout: Duration) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let wait = if filled == 0 { timeout } else { FRAME_TIMEOUT };
+        match transport.read(&mut buf[filled..], wait) {
+            Ok(len) => filled += len,
+            Err(TransportError::Timeout) if filled > 0 => return Err(TransportError::Truncated),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Receives one message into a buffer from `pool`, waiting up to `timeout`
+/// for it to start. Frames that arrive intact but can't be decoded are
+/// reported and skipped (`Ok(None)`); only transport failures are returned
+/// as errors.
+pub fn read_msg(transport: &dyn Transport, pool: &BufferPool, timeout: Duration) -> Result<Option<MsgType>> {
+    Ok(read_msg_with_len(transport, pool, timeout)?.map(|(msg, _)| msg))
+}
+
+/// Like `read_msg`, also returning how many bytes the frame took up.
+pub fn read_msg_with_len(transport: &dyn Transport, pool: &BufferPool, timeout: Duration)
+                         -> Result<Option<(MsgType, usize)>> {
+    let frame = read_frame(transport, pool, timeout)?;
+    let len = frame.data.len();
+    Ok(decode(&frame).map(|msg| (msg, len)))
+}
+
+/// A complete message as it came off the wire, not decoded yet.
+pub struct Frame {
+    pub msg_type: u32,
+    /// The whole message, header included.
+    pub data: Payload,
+    /// Bytes thrown away before this frame because they didn't start with
+    /// a usable header.
+    pub skipped: usize,
+}
+
+/// Checks a header, saying what is wrong with it if it can't be used.
+fn parse_header(buf: &[u8]) -> std::result::Result<BoxMsgHeader, String> {
+    let header: BoxMsgHeader = bincode::deserialize(buf).map_err(|e| format!("malformed header: {}", e))?;
+    if !header.is_valid() {
+        return Err("frame with bad magic/parity".to_string());
+    }
+    if BOX_MSG_HEADER_LEN + header.msg_len as usize > MAX_MSG_LEN {
+        return Err(format!("frame claiming {} bytes", header.msg_len));
+    }
+    Ok(header)
+}
+
+/// How far to move along `buf` for it to start where the next frame might:
+/// at the first byte after the current start from which the rest of `buf`
+/// matches the magic.
+fn next_magic(buf: &[u8]) -> usize {
+    let magic = PROTOCOL_MAGIC.to_le_bytes();
+    (1..buf.len())
+        .find(|&start| buf[start..].iter().zip(&magic).all(|(byte, expected)| byte == expected))
+        .unwrap_or(buf.len())
+}
+
+/// Receives one message without decoding it. A broken header, or one
+/// claiming more than `MAX_MSG_LEN`, is reported and the stream searched
+/// for the next frame's magic, so a glitch costs the frames it hit rather
+/// than the rest of the session.
+pub fn read_frame(transport: &dyn Transport, pool: &BufferPool, timeout: Duration) -> Result<Frame> {
+    let mut buf = pool.get(BOX_MSG_HEADER_LEN);
+    read_exact(transport, &mut buf, timeout)?;
+    let mut skipped = 0;
+    let header = loop {
+        match parse_header(&buf) {
+            Ok(header) => break header,
+            Err(reason) => {
+                if skipped == 0 {
+                    println!("Dropping {}: {:02x?}", reason, &buf[..]);
+                }
+                let shift = next_magic(&buf);
+                buf.copy_within(shift.., 0);
+                read_exact(transport, &mut buf[BOX_MSG_HEADER_LEN - shift..], FRAME_TIMEOUT)?;
+                skipped += shift;
+            }
+        }
+    };
+    if skipped > 0 {
+        println!("Skipped {} bytes to the next frame", skipped);
+    }
+
+    buf.resize(BOX_MSG_HEADER_LEN + header.msg_len as usize);
+    match read_exact(transport, &mut buf[BOX_MSG_HEADER_LEN..], FRAME_TIMEOUT) {
+        Err(TransportError::Timeout) => return Err(TransportError::Truncated),
+        result => result?,
+    }
+
+    Ok(Frame { msg_type: header.msg_type, data: buf.freeze(), skipped })
+}
+
+/// Decodes a frame, reporting it and returning `None` if it can't be.
+pub fn decode(frame: &Frame) -> Option<MsgType> {
+    match MsgType::from_frame(frame.msg_type, &frame.data) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            println!("Dropping undecodable message type {}: {}", frame.msg_type, e);
+            None
+        }
+    }
+}
+
+/// Sends one message, returning the number of bytes written.
+pub fn write_msg(transport: &dyn Transport, msg: &MsgType, timeout: Duration) -> Result<usize> {
+    write_frame(transport, &msg.serialize(), timeout)
+}
+
+/// Sends an already serialized message, returning the number of bytes
+/// written.
+pub fn write_frame(transport: &dyn Transport, buf: &[u8], timeout: Duration) -> Result<usize> {
+    let mut written = 0;
+    while written < buf.len() {
+        written += transport.write(&buf[written..], timeout)?;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link_layer::transport::memory::MemoryTransport;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn messages_round_trip() {
+        let (near, far) = MemoryTransport::pair();
+        let pool = BufferPool::default();
+        let sent = [
+            MsgType::Heartbeat(Heartbeat::new()),
+            MsgType::ButtonCtl(ButtonCtl::new(BUTTON_USE_BOX_MIC)),
+            MsgType::Video(Video::new(800, 480, vec![0, 0, 0, 1, 0x65, 0x88])),
+            MsgType::Audio(Audio::new(5, 1, vec![1, 2, 3, 4, 5, 6])),
+        ];
+        for msg in &sent {
+            let len = write_msg(&near, msg, TIMEOUT).unwrap();
+            assert_eq!(len, msg.serialize().len());
+        }
+        for msg in &sent {
+            let (received, len) = read_msg_with_len(&far, &pool, TIMEOUT).unwrap().unwrap();
+            assert_eq!(received.msg_type(), msg.msg_type());
+            assert_eq!(received.serialize(), msg.serialize());
+            assert_eq!(len, msg.serialize().len());
+        }
+        match read_msg(&far, &pool, TIMEOUT) {
+            Err(TransportError::Timeout) => (),
+            _ => panic!("expected a timeout with nothing sent"),
+        }
+    }
+
+    #[test]
+    fn oversized_frame_is_skipped() {
+        let (near, far) = MemoryTransport::pair();
+        let pool = BufferPool::default();
+        let header = bincode::serialize(&BoxMsgHeader::new(6, u32::MAX - BOX_MSG_HEADER_LEN as u32)).unwrap();
+        write_frame(&near, &header, TIMEOUT).unwrap();
+        // Some of what it claims to carry, out of step with the header size
+        write_frame(&near, &[0x5a; 37], TIMEOUT).unwrap();
+        write_msg(&near, &MsgType::Heartbeat(Heartbeat::new()), TIMEOUT).unwrap();
+        let frame = read_frame(&far, &pool, TIMEOUT).unwrap();
+        assert_eq!(frame.skipped, header.len() + 37);
+        match decode(&frame) {
+            Some(MsgType::Heartbeat(_)) => (),
+            _ => panic!("expected the heartbeat after the oversized frame"),
+        }
+    }
+
+    #[test]
+    fn resyncs_on_the_magic() {
+        let (near, far) = MemoryTransport::pair();
+        let pool = BufferPool::default();
+        // Starts of the magic that go nowhere, then a real frame
+        write_frame(&near, &[0xaa, 0x55, 0x01, 0xaa, 0x55, 0xaa], TIMEOUT).unwrap();
+        write_msg(&near, &MsgType::ButtonCtl(ButtonCtl::new(BUTTON_USE_BOX_MIC)), TIMEOUT).unwrap();
+        write_msg(&near, &MsgType::Heartbeat(Heartbeat::new()), TIMEOUT).unwrap();
+        let frame = read_frame(&far, &pool, TIMEOUT).unwrap();
+        assert_eq!(frame.skipped, 6);
+        assert!(matches!(decode(&frame), Some(MsgType::ButtonCtl(_))));
+        assert!(matches!(read_msg(&far, &pool, TIMEOUT).unwrap(), Some(MsgType::Heartbeat(_))));
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let (near, far) = MemoryTransport::pair();
+        let pool = BufferPool::default();
+        let frame = MsgType::Video(Video::new(800, 480, vec![0; 64])).serialize();
+        write_frame(&near, &frame[..frame.len() - 8], TIMEOUT).unwrap();
+        drop(near);
+        match read_msg(&far, &pool, TIMEOUT) {
+            Err(TransportError::Disconnected) => (),
+            _ => panic!("expected the frame to be cut off"),
+        }
+    }
+}
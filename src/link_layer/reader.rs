@@ -0,0 +1,96 @@
+//! # Message Reader for CarPlay Client
+//!
+//! Owns the incoming side of the transport. A dedicated thread keeps a read
+//! outstanding at all times and hands every decoded message over as soon as
+//! it arrives, so receiving video never waits on anything being sent.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::box_protocol::MsgType;
+use super::buffer_pool::BufferPool;
+use super::capture::{Capture, Direction};
+use super::framing;
+use super::recording::Recorder;
+use super::stats::LinkStats;
+use super::transport::{Result, Transport, TransportError};
+
+/// Upper bound on how long stopping the reader takes.
+const RX_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct Reader {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reader {
+    /// Starts reading into buffers from `pool`. `deliver` gets every
+    /// message, or the error that ended reading; returning `false` from it
+    /// stops the reader. Every frame is also handed to `capture`, and every
+    /// message that decodes to `recorder`.
+    pub fn spawn<F>(transport: Arc<dyn Transport>, pool: BufferPool, stats: LinkStats, capture: Capture,
+                    recorder: Recorder, deliver: F) -> Self
+    where
+        F: FnMut(Result<MsgType>) -> bool + Send + 'static,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || read_loop(&*transport, &pool, &stats, &capture, &recorder, &thread_running, deliver));
+        Self { running, thread: Some(thread) }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn read_loop<F>(transport: &dyn Transport, pool: &BufferPool, stats: &LinkStats, capture: &Capture,
+                recorder: &Recorder, running: &AtomicBool, mut deliver: F)
+where
+    F: FnMut(Result<MsgType>) -> bool,
+{
+    while running.load(Ordering::Relaxed) {
+        let frame = match framing::read_frame(transport, pool, RX_TIMEOUT) {
+            Ok(frame) => {
+                if frame.skipped > 0 {
+                    stats.decode_error();
+                }
+                capture.record(Direction::In, &frame.data);
+                let msg = framing::decode(&frame);
+                if msg.is_some() {
+                    recorder.record(Direction::In, &frame.data);
+                }
+                Ok(msg.map(|msg| (msg, frame.data.len())))
+            }
+            Err(e) => Err(e),
+        };
+        match frame {
+            Ok(Some((msg, len))) => {
+                stats.received(msg.msg_type(), len, matches!(msg, MsgType::Video(_)));
+                if let MsgType::Heartbeat(_) = msg {
+                    stats.heartbeat_received();
+                }
+                if !deliver(Ok(msg)) {
+                    return;
+                }
+            }
+            Ok(None) => stats.decode_error(),
+            // Nothing arriving is fine, the box is just quiet
+            Err(TransportError::Timeout) => {},
+            Err(e) => {
+                if let TransportError::Truncated = e {
+                    stats.timeout();
+                }
+                deliver(Err(e));
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! # Session Recording for CarPlay Client
+//!
+//! A compact native record of a session: every message that was decoded,
+//! or sent, with the time it was seen. Unlike a pcapng capture it can be
+//! fed back through the link layer by `transport::replay`, which is how
+//! field bugs in the player and input layers get reproduced on a desk.
+//!
+//! The file starts with `MAGIC`, followed by one record per message:
+//!
+//! ```text
+//! u64  microseconds since the recording started
+//! u8   0 from the box, 1 to the box
+//! u32  length of the message
+//! ...  the message, header included, as on the wire
+//! ```
+//!
+//! All numbers are little endian.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::box_protocol::{BOX_MSG_HEADER_LEN, MAX_MSG_LEN};
+use super::capture::Direction;
+
+pub const MAGIC: &[u8; 8] = b"CPREC\0\0\x01";
+
+const MAX_RECORD_LEN: usize = MAX_MSG_LEN;
+
+pub struct Record {
+    /// Time since the recording started.
+    pub at: Duration,
+    pub direction: Direction,
+    pub frame: Vec<u8>,
+}
+
+/// Cheap to clone; all clones write to the same recording.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    file: Arc<Mutex<Option<RecordingFile>>>,
+}
+
+struct RecordingFile {
+    out: BufWriter<File>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts recording to `path`, replacing any recording in progress.
+    pub fn start(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        println!("Recording session to {}", path.display());
+        let file = RecordingFile { out, started: Instant::now() };
+        if let Some(mut previous) = self.file.lock().unwrap().replace(file) {
+            let _ = previous.out.flush();
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if let Some(mut file) = self.file.lock().unwrap().take() {
+            if let Err(e) = file.out.flush() {
+                println!("Could not finish recording: {}", e);
+            }
+        }
+    }
+
+    /// Appends one message, header included. A recording that fails to
+    /// write is stopped; the link itself carries on.
+    pub fn record(&self, direction: Direction, frame: &[u8]) {
+        let mut file = self.file.lock().unwrap();
+        let result = match file.as_mut() {
+            Some(recording) => recording.write(direction, frame),
+            None => return,
+        };
+        if let Err(e) = result {
+            println!("Recording stopped: {}", e);
+            *file = None;
+        }
+    }
+}
+
+impl RecordingFile {
+    fn write(&mut self, direction: Direction, frame: &[u8]) -> io::Result<()> {
+        let at = self.started.elapsed().as_micros() as u64;
+        self.out.write_all(&at.to_le_bytes())?;
+        self.out.write_all(&[match direction {
+            Direction::In => 0,
+            Direction::Out => 1,
+        }])?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(frame)
+    }
+}
+
+/// Reads a recording back, record by record.
+pub struct RecordingReader {
+    input: BufReader<File>,
+}
+
+impl RecordingReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a session recording"));
+        }
+        Ok(Self { input })
+    }
+
+    /// The next record, or `None` at the end of the recording.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut head = [0; 13];
+        match self.input.read_exact(&mut head[..1]) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        self.input.read_exact(&mut head[1..])?;
+        let at = u64::from_le_bytes(head[0..8].try_into().unwrap());
+        let direction = match head[8] {
+            0 => Direction::In,
+            1 => Direction::Out,
+            other => return Err(corrupt(format!("unknown direction {}", other))),
+        };
+        let len = u32::from_le_bytes(head[9..13].try_into().unwrap()) as usize;
+        if !(BOX_MSG_HEADER_LEN..=MAX_RECORD_LEN).contains(&len) {
+            return Err(corrupt(format!("bad message length {}", len)));
+        }
+        let mut frame = vec![0; len];
+        self.input.read_exact(&mut frame)?;
+        Ok(Some(Record { at: Duration::from_micros(at), direction, frame }))
+    }
+}
+
+fn corrupt(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt recording: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("carplay-{}-{}.rec", name, std::process::id()))
+    }
+
+    /// A file holding `MAGIC` and one record with the given direction byte
+    /// and length field, followed by `len` bytes.
+    fn write_raw(path: &Path, direction: u8, len: u32) {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1000u64.to_le_bytes());
+        data.push(direction);
+        data.extend_from_slice(&len.to_le_bytes());
+        data.resize(data.len() + len.min(1024) as usize, 0);
+        fs::write(path, data).unwrap();
+    }
+
+    fn first_record(path: &Path) -> io::Result<Option<Record>> {
+        let result = RecordingReader::open(path).and_then(|mut reader| reader.next_record());
+        fs::remove_file(path).unwrap();
+        result
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let path = temp_path("round-trip");
+        let frames = [(Direction::In, vec![0x55; BOX_MSG_HEADER_LEN]),
+                      (Direction::Out, (0..200).map(|n| n as u8).collect())];
+        let recorder = Recorder::new();
+        recorder.start(&path).unwrap();
+        for (direction, frame) in &frames {
+            recorder.record(*direction, frame);
+        }
+        recorder.stop();
+        // Not recording any more
+        recorder.record(Direction::In, &[0; BOX_MSG_HEADER_LEN]);
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        let mut last_at = Duration::ZERO;
+        for (direction, frame) in &frames {
+            let record = reader.next_record().unwrap().unwrap();
+            assert_eq!(record.direction, *direction);
+            assert_eq!(&record.frame, frame);
+            assert!(record.at >= last_at);
+            last_at = record.at;
+        }
+        assert!(reader.next_record().unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_length() {
+        for &len in &[0, BOX_MSG_HEADER_LEN as u32 - 1, MAX_RECORD_LEN as u32 + 1] {
+            let path = temp_path("length");
+            write_raw(&path, 0, len);
+            let error = first_record(&path).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("bad message length"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn unknown_direction() {
+        let path = temp_path("direction");
+        write_raw(&path, 2, BOX_MSG_HEADER_LEN as u32);
+        let error = first_record(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("unknown direction 2"), "{}", error);
+    }
+
+    #[test]
+    fn not_a_recording() {
+        let path = temp_path("magic");
+        fs::write(&path, b"RIFF\0\0\0\0WAVE").unwrap();
+        let error = RecordingReader::open(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,151 @@
+//! # Session State for CarPlay Client
+//!
+//! Tracks the lifecycle of the connection to the AutoBox Server hardware.
+//! The link layer feeds `SessionEvent`s into the state machine and acts on
+//! the resulting `SessionState`; every transition is reported to the other
+//! layers so they can react to the phone appearing or the dongle rebooting.
+
+use std::time::Duration;
+
+/// How long the box has to answer the startup sequence before we give up
+/// on it and start over.
+pub const OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Without a `Video` message for this long we no longer consider the
+/// session to be streaming.
+pub const STREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+const RECOVERY_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECOVERY_BACKOFF_MAX: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// No dongle has been found yet; scanning the bus.
+    Searching,
+    /// Dongle found; claiming it and running the startup sequence.
+    Opening,
+    /// Dongle is up, but no phone is attached.
+    Idle,
+    /// Phone attached (`DevPlug`), no video flowing yet.
+    PhoneConnected,
+    /// Phone attached and `Video` messages are arriving.
+    Streaming,
+    /// Something went wrong; the dongle is released and we back off before
+    /// searching again.
+    Recovering,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    DeviceFound,
+    /// The startup sequence was sent and the box answered.
+    BoxResponded,
+    PhonePlugged,
+    PhoneUnplugged,
+    VideoReceived,
+    OpenTimeout,
+    StreamTimeout,
+    /// Any USB failure (transfer error, disconnect, failed setup step).
+    TransferError,
+    BackoffElapsed,
+}
+
+impl SessionState {
+    /// Returns the state the session moves to when `event` happens in the
+    /// current state. Events that don't apply leave the state unchanged.
+    pub fn next(self, event: SessionEvent) -> SessionState {
+        use SessionEvent::*;
+        use SessionState::*;
+        match (self, event) {
+            (Recovering, BackoffElapsed) => Searching,
+            (Recovering, _) => Recovering,
+            (_, TransferError) => Recovering,
+            (Searching, DeviceFound) => Opening,
+            (Opening, BoxResponded) => Idle,
+            (Opening, OpenTimeout) => Recovering,
+            (Idle, PhonePlugged) => PhoneConnected,
+            (PhoneConnected, VideoReceived) => Streaming,
+            (Streaming, StreamTimeout) => PhoneConnected,
+            (PhoneConnected, PhoneUnplugged) | (Streaming, PhoneUnplugged) => Idle,
+            (state, _) => state,
+        }
+    }
+}
+
+/// Exponential backoff used while in `SessionState::Recovering`; a dongle that
+/// is rebooting takes a few seconds to re-enumerate, one that is wedged
+/// shouldn't be hammered.
+pub struct Backoff {
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { current: RECOVERY_BACKOFF_MIN }
+    }
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current * 2, RECOVERY_BACKOFF_MAX);
+        delay
+    }
+    pub fn reset(&mut self) {
+        self.current = RECOVERY_BACKOFF_MIN;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SessionEvent::*;
+    use SessionState::*;
+
+    #[test]
+    fn transitions() {
+        let table = [
+            (Searching, DeviceFound, Opening),
+            (Searching, PhonePlugged, Searching),
+            (Searching, TransferError, Recovering),
+            (Opening, BoxResponded, Idle),
+            (Opening, OpenTimeout, Recovering),
+            (Opening, TransferError, Recovering),
+            (Opening, VideoReceived, Opening),
+            (Idle, PhonePlugged, PhoneConnected),
+            (Idle, VideoReceived, Idle),
+            (Idle, TransferError, Recovering),
+            (PhoneConnected, VideoReceived, Streaming),
+            (PhoneConnected, PhoneUnplugged, Idle),
+            (PhoneConnected, StreamTimeout, PhoneConnected),
+            (Streaming, StreamTimeout, PhoneConnected),
+            (Streaming, PhoneUnplugged, Idle),
+            (Streaming, VideoReceived, Streaming),
+            (Streaming, TransferError, Recovering),
+            (Recovering, BackoffElapsed, Searching),
+            (Recovering, DeviceFound, Recovering),
+            (Recovering, TransferError, Recovering),
+            (Searching, BackoffElapsed, Searching),
+        ];
+        for &(state, event, expected) in &table {
+            assert_eq!(state.next(event), expected, "{:?} on {:?}", state, event);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<_> = (0..8).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays[0], RECOVERY_BACKOFF_MIN);
+        assert_eq!(delays[1], RECOVERY_BACKOFF_MIN * 2);
+        assert!(delays.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert_eq!(*delays.last().unwrap(), RECOVERY_BACKOFF_MAX);
+        assert_eq!(backoff.next_delay(), RECOVERY_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn backoff_reset_starts_over() {
+        let mut backoff = Backoff::new();
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), RECOVERY_BACKOFF_MIN);
+    }
+}
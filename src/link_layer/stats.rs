@@ -0,0 +1,164 @@
+//! # Link Statistics for CarPlay Client
+//!
+//! Counters kept by the reader, the writer and the session, so there is
+//! something to look at when video stutters. `LinkStats` is shared between
+//! those threads; `snapshot` copies everything out at once.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::box_protocol::msg_type_name;
+use super::buffer_pool::{BufferPool, PoolStats};
+
+/// Video rate figures are averaged over this much recent traffic.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MsgCounter {
+    pub messages: u64,
+    /// Bytes on the wire, headers included.
+    pub bytes: u64,
+}
+
+impl MsgCounter {
+    fn add(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StatsSnapshot {
+    /// Received messages by message type number.
+    pub received: BTreeMap<u32, MsgCounter>,
+    /// Sent messages by message type number.
+    pub sent: BTreeMap<u32, MsgCounter>,
+    /// Frames that arrived intact but could not be decoded, and runs of
+    /// bytes skipped to find the next frame after a broken header.
+    pub decode_errors: u64,
+    /// Writes that timed out and messages cut short by a stalled read.
+    pub timeouts: u64,
+    /// Connections made after the first one.
+    pub reconnects: u64,
+    /// Time since the box last sent a `Heartbeat`; `None` until it has.
+    /// This stands in for a heartbeat round trip: the box sends heartbeats
+    /// on its own timer instead of answering ours, so timing ours against
+    /// its next one only measures how the two timers line up. A gap well
+    /// past the heartbeat interval means the box has gone quiet.
+    pub since_heartbeat: Option<Duration>,
+    pub video_fps: f32,
+    pub video_bitrate: u64, // bits per second
+    pub buffers: PoolStats,
+}
+
+impl StatsSnapshot {
+    fn total(counters: &BTreeMap<u32, MsgCounter>) -> MsgCounter {
+        counters.values().fold(MsgCounter::default(), |total, counter| MsgCounter {
+            messages: total.messages + counter.messages,
+            bytes: total.bytes + counter.bytes,
+        })
+    }
+    pub fn total_received(&self) -> MsgCounter {
+        StatsSnapshot::total(&self.received)
+    }
+    pub fn total_sent(&self) -> MsgCounter {
+        StatsSnapshot::total(&self.sent)
+    }
+}
+
+/// One line summary, followed by a line per message type.
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (received, sent) = (self.total_received(), self.total_sent());
+        write!(f, "video {:.1} fps {:.2} Mbit/s, in {} msgs {} bytes, out {} msgs {} bytes, \
+                   {} decode errors, {} timeouts, {} reconnects, last heartbeat from the box ",
+               self.video_fps, self.video_bitrate as f64 / 1e6,
+               received.messages, received.bytes, sent.messages, sent.bytes,
+               self.decode_errors, self.timeouts, self.reconnects)?;
+        match self.since_heartbeat {
+            Some(since) => write!(f, "{} ms ago", since.as_millis())?,
+            None => write!(f, "n/a")?,
+        }
+        write!(f, ", buffers {} in use (peak {}), {} reused, {} allocated",
+               self.buffers.in_use, self.buffers.peak_in_use, self.buffers.reused, self.buffers.exhausted)?;
+        for (direction, counters) in &[("in", &self.received), ("out", &self.sent)] {
+            for (&msg_type, counter) in counters.iter() {
+                write!(f, "\n  {:>3} {:<10} {:>8} msgs {:>12} bytes", direction,
+                       msg_type_name(msg_type).unwrap_or("unknown"), counter.messages, counter.bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    snapshot: StatsSnapshot,
+    heartbeat_received: Option<Instant>,
+    /// Arrival time and size of recent video messages.
+    video: VecDeque<(Instant, usize)>,
+}
+
+/// Cheap to clone; all clones update the same counters.
+#[derive(Clone)]
+pub struct LinkStats {
+    counters: Arc<Mutex<Counters>>,
+    /// Reported alongside, it is where received messages live.
+    pool: BufferPool,
+}
+
+impl LinkStats {
+    pub fn new(pool: BufferPool) -> Self {
+        Self { counters: Arc::new(Mutex::new(Counters::default())), pool }
+    }
+
+    pub fn received(&self, msg_type: u32, bytes: usize, is_video: bool) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.snapshot.received.entry(msg_type).or_default().add(bytes);
+        if is_video {
+            let now = Instant::now();
+            counters.video.push_back((now, bytes));
+            trim(&mut counters.video, now);
+        }
+    }
+    pub fn sent(&self, msg_type: u32, bytes: usize) {
+        self.counters.lock().unwrap().snapshot.sent.entry(msg_type).or_default().add(bytes);
+    }
+    pub fn decode_error(&self) {
+        self.counters.lock().unwrap().snapshot.decode_errors += 1;
+    }
+    pub fn timeout(&self) {
+        self.counters.lock().unwrap().snapshot.timeouts += 1;
+    }
+    pub fn reconnect(&self) {
+        self.counters.lock().unwrap().snapshot.reconnects += 1;
+    }
+    pub fn heartbeat_received(&self) {
+        self.counters.lock().unwrap().heartbeat_received = Some(Instant::now());
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let mut counters = self.counters.lock().unwrap();
+        trim(&mut counters.video, Instant::now());
+        let window = RATE_WINDOW.as_secs_f32();
+        let video_bytes: usize = counters.video.iter().map(|&(_, bytes)| bytes).sum();
+        StatsSnapshot {
+            video_fps: counters.video.len() as f32 / window,
+            video_bitrate: (video_bytes as f32 * 8.0 / window) as u64,
+            since_heartbeat: counters.heartbeat_received.map(|at| at.elapsed()),
+            buffers: self.pool.stats(),
+            ..counters.snapshot.clone()
+        }
+    }
+}
+
+fn trim(video: &mut VecDeque<(Instant, usize)>, now: Instant) {
+    while let Some(&(at, _)) = video.front() {
+        if now.duration_since(at) <= RATE_WINDOW {
+            break;
+        }
+        video.pop_front();
+    }
+}
@@ -0,0 +1,85 @@
+//! # Transports for CarPlay Client
+//!
+//! Moves raw bytes between the client and the AutoBox Server hardware.
+//! Everything above this module (framing, heartbeat, session) only sees the
+//! `Transport` trait, so the same link layer runs over USB, over a socket to
+//! a dongle emulator or remote box, or over an in-memory pipe.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+extern crate rusb;
+
+use super::error::LinkError;
+
+pub mod memory;
+pub mod replay;
+pub mod socket;
+pub mod usb;
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// Nothing arrived (or could be sent) within the timeout.
+    Timeout,
+    /// The other end went away.
+    Disconnected,
+    /// The stream ended or stalled in the middle of a message.
+    Truncated,
+    Usb(rusb::Error),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, TransportError>;
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Timeout => write!(f, "timed out"),
+            TransportError::Disconnected => write!(f, "disconnected"),
+            TransportError::Truncated => write!(f, "message truncated"),
+            TransportError::Usb(e) => write!(f, "USB error: {}", e),
+            TransportError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<rusb::Error> for TransportError {
+    fn from(e: rusb::Error) -> Self {
+        match e {
+            rusb::Error::Timeout => TransportError::Timeout,
+            rusb::Error::NoDevice => TransportError::Disconnected,
+            e => TransportError::Usb(e),
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => TransportError::Timeout,
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => TransportError::Disconnected,
+            _ => TransportError::Io(e),
+        }
+    }
+}
+
+/// A bidirectional byte pipe to the box. Methods take `&self` so one
+/// transport can be shared between a reading and a writing thread.
+pub trait Transport: Send + Sync {
+    /// Reads whatever is available into `buf`, waiting at most `timeout` for
+    /// the first byte. Returns the number of bytes read (never 0).
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize>;
+    /// Writes some prefix of `buf`, returning how much was accepted.
+    fn write(&self, buf: &[u8], timeout: Duration) -> Result<usize>;
+}
+
+/// Produces transports for the session; called repeatedly while searching
+/// for the box and again after every recovery.
+pub trait Connector: Send {
+    /// Returns `Ok(None)` if there is nothing to connect to yet.
+    fn connect(&mut self) -> std::result::Result<Option<Box<dyn Transport>>, LinkError>;
+}
@@ -0,0 +1,78 @@
+//! # In-Memory Transport for CarPlay Client
+//!
+//! A connected pair of transports backed by channels, for driving the link
+//! layer without any hardware.
+
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use super::{Connector, LinkError, Result, Transport, TransportError};
+
+struct Incoming {
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+pub struct MemoryTransport {
+    tx: Mutex<Sender<Vec<u8>>>,
+    incoming: Mutex<Incoming>,
+}
+
+impl MemoryTransport {
+    /// Returns two transports; whatever is written to one is read from the
+    /// other.
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (tx_a, rx_a) = mpsc::channel();
+        let (tx_b, rx_b) = mpsc::channel();
+        (MemoryTransport::new(tx_a, rx_b), MemoryTransport::new(tx_b, rx_a))
+    }
+    fn new(tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> Self {
+        Self {
+            tx: Mutex::new(tx),
+            incoming: Mutex::new(Incoming { rx, pending: Vec::new(), offset: 0 }),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.offset == incoming.pending.len() {
+            incoming.pending = match incoming.rx.recv_timeout(timeout) {
+                Ok(data) => data,
+                Err(RecvTimeoutError::Timeout) => return Err(TransportError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(TransportError::Disconnected),
+            };
+            incoming.offset = 0;
+        }
+        let len = std::cmp::min(buf.len(), incoming.pending.len() - incoming.offset);
+        let start = incoming.offset;
+        buf[..len].copy_from_slice(&incoming.pending[start..start + len]);
+        incoming.offset += len;
+        Ok(len)
+    }
+    fn write(&self, buf: &[u8], _timeout: Duration) -> Result<usize> {
+        self.tx.lock().unwrap().send(buf.to_vec())
+                               .map_err(|_| TransportError::Disconnected)?;
+        Ok(buf.len())
+    }
+}
+
+/// Hands out a single pre-made transport; later connects find nothing.
+pub struct MemoryConnector {
+    transport: Option<MemoryTransport>,
+}
+
+impl MemoryConnector {
+    pub fn new(transport: MemoryTransport) -> Self {
+        Self { transport: Some(transport) }
+    }
+}
+
+impl Connector for MemoryConnector {
+    fn connect(&mut self) -> std::result::Result<Option<Box<dyn Transport>>, LinkError> {
+        Ok(self.transport.take().map(|transport| Box::new(transport) as Box<dyn Transport>))
+    }
+}
@@ -0,0 +1,124 @@
+//! # Replay Transport for CarPlay Client
+//!
+//! Plays a session recording back as if the box were sending it, at the
+//! recorded pace or scaled by a speed factor. Whatever the client sends is
+//! accepted and thrown away. When the recording ends the transport
+//! disconnects and the client is asked to shut down, so a replay runs the
+//! whole pipeline once and exits.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Connector, LinkError, Result, Transport, TransportError};
+use crate::link_layer::capture::Direction;
+use crate::link_layer::recording::RecordingReader;
+use crate::signal;
+
+struct Playback {
+    recording: RecordingReader,
+    started: Instant,
+    /// The message being handed out, and how much of it already was.
+    pending: Vec<u8>,
+    offset: usize,
+    due: Instant,
+    finished: bool,
+}
+
+pub struct ReplayTransport {
+    playback: Mutex<Playback>,
+    /// 1.0 is real time, 2.0 twice as fast; 0 doesn't wait at all.
+    speed: f32,
+}
+
+impl ReplayTransport {
+    pub fn new(recording: RecordingReader, speed: f32) -> Self {
+        let now = Instant::now();
+        let playback = Playback {
+            recording,
+            started: now,
+            pending: Vec::new(),
+            offset: 0,
+            due: now,
+            finished: false,
+        };
+        Self { playback: Mutex::new(playback), speed }
+    }
+
+    /// Loads the next message from the box, skipping what the client sent.
+    fn next_incoming(&self, playback: &mut Playback) -> Result<()> {
+        loop {
+            let record = match playback.recording.next_record()? {
+                Some(record) => record,
+                None => {
+                    playback.finished = true;
+                    return Err(TransportError::Disconnected);
+                }
+            };
+            if record.direction == Direction::In {
+                playback.due = match self.speed > 0.0 {
+                    true => playback.started + record.at.div_f32(self.speed),
+                    false => playback.started,
+                };
+                playback.pending = record.frame;
+                playback.offset = 0;
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let mut playback = self.playback.lock().unwrap();
+        if playback.finished {
+            return Err(TransportError::Disconnected);
+        }
+        if playback.offset == playback.pending.len() {
+            self.next_incoming(&mut playback)?;
+        }
+        let wait = playback.due.saturating_duration_since(Instant::now());
+        if wait > timeout {
+            thread::sleep(timeout);
+            return Err(TransportError::Timeout);
+        }
+        thread::sleep(wait);
+        let len = std::cmp::min(buf.len(), playback.pending.len() - playback.offset);
+        let start = playback.offset;
+        buf[..len].copy_from_slice(&playback.pending[start..start + len]);
+        playback.offset += len;
+        Ok(len)
+    }
+    fn write(&self, buf: &[u8], _timeout: Duration) -> Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// Replays the recording once; after that there is nothing to connect to.
+pub struct ReplayConnector {
+    path: PathBuf,
+    speed: f32,
+    played: bool,
+}
+
+impl ReplayConnector {
+    pub fn new(path: PathBuf, speed: f32) -> Self {
+        Self { path, speed, played: false }
+    }
+}
+
+impl Connector for ReplayConnector {
+    fn connect(&mut self) -> std::result::Result<Option<Box<dyn Transport>>, LinkError> {
+        if self.played {
+            println!("Replay of {} finished", self.path.display());
+            signal::request_shutdown();
+            return Ok(None);
+        }
+        let recording = RecordingReader::open(&self.path)
+            .map_err(|source| LinkError::Recording { path: self.path.clone(), source })?;
+        self.played = true;
+        println!("Replaying {} at {}x", self.path.display(), self.speed);
+        Ok(Some(Box::new(ReplayTransport::new(recording, self.speed))))
+    }
+}
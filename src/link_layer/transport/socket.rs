@@ -0,0 +1,116 @@
+//! # Socket Transport for CarPlay Client
+//!
+//! Carries the box protocol over a stream socket, e.g. to a dongle emulator
+//! or to a remote machine relaying the dongle's bulk endpoints.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::{Connector, LinkError, Result, Transport, TransportError};
+
+/// The parts of a connected stream socket the transport needs.
+pub trait SocketStream: Send + Sync {
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+    fn send(&self, buf: &[u8], timeout: Duration) -> io::Result<usize>;
+}
+
+impl SocketStream for TcpStream {
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.set_read_timeout(Some(timeout))?;
+        (&*self).read(buf)
+    }
+    fn send(&self, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.set_write_timeout(Some(timeout))?;
+        (&*self).write(buf)
+    }
+}
+
+impl SocketStream for UnixStream {
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.set_read_timeout(Some(timeout))?;
+        (&*self).read(buf)
+    }
+    fn send(&self, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.set_write_timeout(Some(timeout))?;
+        (&*self).write(buf)
+    }
+}
+
+pub struct SocketTransport<S: SocketStream> {
+    stream: S,
+}
+
+pub type TcpTransport = SocketTransport<TcpStream>;
+pub type UnixTransport = SocketTransport<UnixStream>;
+
+impl<S: SocketStream> SocketTransport<S> {
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+}
+
+impl<S: SocketStream> Transport for SocketTransport<S> {
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        match self.stream.recv(buf, timeout)? {
+            0 => Err(TransportError::Disconnected),
+            len => Ok(len),
+        }
+    }
+    fn write(&self, buf: &[u8], timeout: Duration) -> Result<usize> {
+        Ok(self.stream.send(buf, timeout)?)
+    }
+}
+
+/// Nobody listening yet is not an error, just nothing to connect to.
+fn not_listening(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound)
+}
+
+/// Connects to `addr` (`host:port`), retrying while nobody is listening.
+pub struct TcpConnector {
+    addr: String,
+}
+
+impl TcpConnector {
+    pub fn new(addr: String) -> Self {
+        Self { addr }
+    }
+}
+
+impl Connector for TcpConnector {
+    fn connect(&mut self) -> std::result::Result<Option<Box<dyn Transport>>, LinkError> {
+        match TcpStream::connect(&self.addr) {
+            Ok(stream) => {
+                // Touch events are tiny and latency sensitive
+                stream.set_nodelay(true)?;
+                Ok(Some(Box::new(TcpTransport::new(stream))))
+            }
+            Err(ref e) if not_listening(e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Connects to a Unix domain socket at `path`.
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+impl UnixConnector {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Connector for UnixConnector {
+    fn connect(&mut self) -> std::result::Result<Option<Box<dyn Transport>>, LinkError> {
+        match UnixStream::connect(&self.path) {
+            Ok(stream) => Ok(Some(Box::new(UnixTransport::new(stream)))),
+            Err(ref e) if not_listening(e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! # USB Transport for CarPlay Client
+//!
+//! Talks to the AutoBox Server hardware through a pair of bulk endpoints.
+//! Reads go through a ring of asynchronous transfers (see `bulk_in`), writes
+//! are plain synchronous bulk transfers.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rusb::*;
+
+use super::{Connector, Transport, TransportError};
+use crate::link_layer::error::LinkError;
+use crate::link_layer::device::{self, DeviceFilter};
+
+mod bulk_in;
+use bulk_in::BulkIn;
+pub use bulk_in::BulkInConfig;
+
+pub struct UsbTransport {
+    // Taken in `drop` so the transfers are gone before the interface is
+    bulk_in: Mutex<Option<BulkIn>>,
+    device_handle: DeviceHandle<Context>,
+    ep_out: u8,
+    interface: u8,
+    claimed: bool,
+    /// Whether a kernel driver was bound to the interface before us.
+    reattach_driver: bool,
+}
+
+impl UsbTransport {
+    pub fn open(device: &Device<Context>, bulk_in: BulkInConfig) -> std::result::Result<Self, LinkError> {
+        let device_desc = device.device_descriptor()?;
+        let mut device_handle = device.open().map_err(|e| match e {
+            Error::Access => LinkError::PermissionDenied {
+                vendor_id: device_desc.vendor_id(),
+                product_id: device_desc.product_id(),
+            },
+            e => LinkError::Usb(e),
+        })?;
+
+        device_handle.reset()?;
+        device_handle.set_active_configuration(1)?; // Config 1 is the first valid one
+
+        let config_desc = device_handle.device().config_descriptor(0)?; // Config _index_
+        let endpoints = find_bulk_endpoints(&config_desc)?;
+        let interface = endpoints.interface;
+
+        let reattach_driver = matches!(device_handle.kernel_driver_active(interface), Ok(true));
+        if reattach_driver {
+            device_handle.detach_kernel_driver(interface)?;
+        }
+        // From here on, failing drops the transport, which hands the
+        // interface back to the kernel
+        let mut transport = Self {
+            bulk_in: Mutex::new(None),
+            device_handle,
+            ep_out: endpoints.ep_out,
+            interface,
+            claimed: false,
+            reattach_driver,
+        };
+        transport.device_handle.claim_interface(interface)
+                               .map_err(|source| LinkError::ClaimFailed { interface, source })?;
+        transport.claimed = true;
+        if endpoints.alt_setting != 0 {
+            transport.device_handle.set_alternate_setting(interface, endpoints.alt_setting)?;
+        }
+
+        transport.device_handle.clear_halt(endpoints.ep_in)?;
+        transport.device_handle.clear_halt(endpoints.ep_out)?;
+
+        println!("Claimed interface {} alt setting {}, bulk IN {:#04x} ({} bytes), bulk OUT {:#04x} ({} bytes)",
+                 interface, endpoints.alt_setting, endpoints.ep_in, endpoints.max_packet_in,
+                 endpoints.ep_out, endpoints.max_packet_out);
+
+        let reads = BulkIn::new(&transport.device_handle, endpoints.ep_in, bulk_in)?;
+        *transport.bulk_in.get_mut().unwrap() = Some(reads);
+        Ok(transport)
+    }
+}
+
+/// Where the dongle's bulk pipe lives.
+struct BulkEndpoints {
+    interface: u8,
+    alt_setting: u8,
+    ep_in: u8,
+    ep_out: u8,
+    max_packet_in: u16,
+    max_packet_out: u16,
+}
+
+/// Packet sizes the USB spec allows for bulk endpoints, across full, high
+/// and super speed. Anything else means a broken descriptor.
+fn valid_bulk_packet_size(endpoint_desc: &EndpointDescriptor) -> bool {
+    // Bulk endpoints never set the extra-transactions bits, so those fail too
+    matches!(endpoint_desc.max_packet_size(), 8 | 16 | 32 | 64 | 512 | 1024)
+}
+
+fn describe_endpoint(endpoint_desc: &EndpointDescriptor) -> String {
+    format!("{:?} {:#04x} {:?} {} bytes", endpoint_desc.direction(), endpoint_desc.address(),
+            endpoint_desc.transfer_type(), endpoint_desc.max_packet_size())
+}
+
+/// Looks through every interface and alternate setting for the first one
+/// with a usable bulk IN and bulk OUT endpoint. Interrupt and isochronous
+/// endpoints, which some clones add, are passed over.
+fn find_bulk_endpoints(config_desc: &ConfigDescriptor) -> std::result::Result<BulkEndpoints, LinkError> {
+    let mut layout = Vec::new();
+    for iface in config_desc.interfaces() {
+        for iface_desc in iface.descriptors() {
+            let mut ep_in: Option<EndpointDescriptor> = None;
+            let mut ep_out: Option<EndpointDescriptor> = None;
+            for endpoint_desc in iface_desc.endpoint_descriptors() {
+                layout.push(format!("interface {} alt {}: {}", iface_desc.interface_number(),
+                                    iface_desc.setting_number(), describe_endpoint(&endpoint_desc)));
+                if endpoint_desc.transfer_type() != TransferType::Bulk || !valid_bulk_packet_size(&endpoint_desc) {
+                    continue;
+                }
+                let slot = match endpoint_desc.direction() {
+                    Direction::In => &mut ep_in,
+                    Direction::Out => &mut ep_out,
+                };
+                if slot.is_none() {
+                    *slot = Some(endpoint_desc);
+                }
+            }
+            if let (Some(ep_in), Some(ep_out)) = (ep_in, ep_out) {
+                return Ok(BulkEndpoints {
+                    interface: iface_desc.interface_number(),
+                    alt_setting: iface_desc.setting_number(),
+                    ep_in: ep_in.address(),
+                    ep_out: ep_out.address(),
+                    max_packet_in: ep_in.max_packet_size(),
+                    max_packet_out: ep_out.max_packet_size(),
+                });
+            }
+        }
+    }
+    Err(LinkError::NoBulkEndpoints { layout })
+}
+
+impl Transport for UsbTransport {
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> super::Result<usize> {
+        match self.bulk_in.lock().unwrap().as_mut() {
+            Some(bulk_in) => bulk_in.read(buf, timeout),
+            None => Err(TransportError::Disconnected),
+        }
+    }
+    fn write(&self, buf: &[u8], timeout: Duration) -> super::Result<usize> {
+        Ok(self.device_handle.write_bulk(self.ep_out, buf, timeout)?)
+    }
+}
+
+impl Drop for UsbTransport {
+    fn drop(&mut self) {
+        // Cancels and reaps the outstanding reads
+        self.bulk_in.get_mut().unwrap_or_else(|e| e.into_inner()).take();
+        if self.claimed {
+            if let Err(e) = self.device_handle.release_interface(self.interface) {
+                println!("Could not release interface {}: {}", self.interface, e);
+            }
+        }
+        if self.reattach_driver {
+            if let Err(e) = self.device_handle.attach_kernel_driver(self.interface) {
+                println!("Could not reattach the kernel driver: {}", e);
+            }
+        }
+    }
+}
+
+/// Scans the bus for a dongle accepted by the filter and opens it.
+pub struct UsbConnector {
+    usb_ctx: Context,
+    filter: DeviceFilter,
+    bulk_in: BulkInConfig,
+    /// Bus and address of the devices that last matched.
+    listed: Vec<(u8, u8)>,
+}
+
+impl UsbConnector {
+    pub fn new(filter: DeviceFilter, bulk_in: BulkInConfig) -> std::result::Result<Self, LinkError> {
+        let usb_ctx = Context::new().map_err(LinkError::UsbInit)?;
+        Ok(Self { usb_ctx, filter, bulk_in, listed: Vec::new() })
+    }
+}
+
+impl Connector for UsbConnector {
+    fn connect(&mut self) -> std::result::Result<Option<Box<dyn Transport>>, LinkError> {
+        match self.filter.find(&self.usb_ctx, &mut self.listed)? {
+            Some(device) => Ok(Some(Box::new(UsbTransport::open(&device, self.bulk_in)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Prints every attached device accepted by `filter`.
+pub fn list_devices(filter: &DeviceFilter) -> std::result::Result<(), LinkError> {
+    let usb_ctx = Context::new().map_err(LinkError::UsbInit)?;
+    let devices = filter.find_all(&usb_ctx)?;
+    if devices.is_empty() {
+        return Err(LinkError::DeviceNotFound);
+    }
+    for device in devices {
+        println!("{}", device::describe(&device));
+    }
+    Ok(())
+}
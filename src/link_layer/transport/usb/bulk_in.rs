@@ -0,0 +1,240 @@
+//! # Asynchronous Bulk Reads for CarPlay Client
+//!
+//! A synchronous `read_bulk` leaves the bus idle from the moment it returns
+//! until the next call is made. Instead, a ring of bulk-IN transfers is kept
+//! submitted at all times so the host controller can fill them back to
+//! back. Transfers on one endpoint complete in the order they were
+//! submitted, so handing them out oldest first reassembles the stream. Reads
+//! copy straight out of a completed transfer's buffer, and the transfer is
+//! resubmitted once all of it has been read.
+
+use std::cmp;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+use libc::{c_int, c_void, timeval};
+use libusb1_sys::*;
+use libusb1_sys::constants::*;
+use rusb::{Context, DeviceHandle, UsbContext};
+
+use crate::link_layer::transport::{Result, TransportError};
+
+/// How long dropping waits for cancelled transfers to come back.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sizing of the ring of in-flight reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkInConfig {
+    /// Number of transfers kept submitted.
+    pub transfers: usize,
+    /// Size of each transfer in bytes.
+    pub transfer_size: usize,
+}
+
+impl Default for BulkInConfig {
+    fn default() -> Self {
+        // The largest packet we tell the box we accept in OpenBox
+        Self { transfers: 4, transfer_size: 49152 }
+    }
+}
+
+struct Slot {
+    transfer: *mut libusb_transfer,
+    buffer: Vec<u8>,
+    /// Set by `on_complete`; libusb's event loop watches it too. Boxed
+    /// so it stays put while the ring grows.
+    completed: Box<AtomicI32>,
+    submitted: bool,
+}
+
+pub struct BulkIn {
+    context: *mut libusb_context,
+    slots: Vec<Slot>,
+    /// The oldest submitted transfer, which is the next to complete.
+    next: usize,
+    /// The completed transfer being read out, not resubmitted yet.
+    current: Option<usize>,
+    /// How much data it holds, and how much of that was read.
+    current_len: usize,
+    current_pos: usize,
+}
+
+// The raw pointers are only touched while `UsbTransport` holds its lock,
+// and libusb itself is thread safe.
+unsafe impl Send for BulkIn {}
+
+extern "system" fn on_complete(transfer: *mut libusb_transfer) {
+    unsafe {
+        let completed = &*((*transfer).user_data as *const AtomicI32);
+        completed.store(1, Ordering::Release);
+    }
+}
+
+fn usb_error(code: c_int) -> rusb::Error {
+    match code {
+        LIBUSB_ERROR_IO => rusb::Error::Io,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+fn transfer_error(status: c_int) -> TransportError {
+    match status {
+        LIBUSB_TRANSFER_NO_DEVICE | LIBUSB_TRANSFER_CANCELLED => TransportError::Disconnected,
+        LIBUSB_TRANSFER_TIMED_OUT => TransportError::Timeout,
+        LIBUSB_TRANSFER_STALL => TransportError::Usb(rusb::Error::Pipe),
+        LIBUSB_TRANSFER_OVERFLOW => TransportError::Usb(rusb::Error::Overflow),
+        _ => TransportError::Usb(rusb::Error::Io),
+    }
+}
+
+fn to_timeval(duration: Duration) -> timeval {
+    timeval {
+        tv_sec: duration.as_secs() as _,
+        tv_usec: duration.subsec_micros() as _,
+    }
+}
+
+impl BulkIn {
+    /// Allocates and submits `config.transfers` reads on `endpoint`.
+    pub fn new(handle: &DeviceHandle<Context>, endpoint: u8, config: BulkInConfig) -> rusb::Result<Self> {
+        let mut bulk_in = BulkIn {
+            context: handle.context().as_raw(),
+            slots: Vec::new(),
+            next: 0,
+            current: None,
+            current_len: 0,
+            current_pos: 0,
+        };
+        for index in 0..cmp::max(config.transfers, 1) {
+            let transfer = unsafe { libusb_alloc_transfer(0) };
+            if transfer.is_null() {
+                return Err(rusb::Error::NoMem);
+            }
+            let mut slot = Slot {
+                transfer,
+                buffer: vec![0; config.transfer_size],
+                completed: Box::new(AtomicI32::new(0)),
+                submitted: false,
+            };
+            unsafe {
+                libusb_fill_bulk_transfer(transfer, handle.as_raw(), endpoint,
+                                          slot.buffer.as_mut_ptr(), slot.buffer.len() as c_int,
+                                          on_complete, slot.completed.as_ptr() as *mut c_void,
+                                          0); // Never time out, reads just wait for data
+            }
+            // Owned by the ring before submitting, so a failure cleans it up
+            bulk_in.slots.push(slot);
+            bulk_in.submit(index)?;
+        }
+        Ok(bulk_in)
+    }
+
+    fn submit(&mut self, index: usize) -> rusb::Result<()> {
+        let slot = &mut self.slots[index];
+        slot.completed.store(0, Ordering::Release);
+        match unsafe { libusb_submit_transfer(slot.transfer) } {
+            0 => {
+                slot.submitted = true;
+                Ok(())
+            }
+            code => Err(usb_error(code)),
+        }
+    }
+
+    /// Runs libusb's event loop until `slot` completes or `deadline` passes.
+    fn wait_for(&self, index: usize, deadline: Instant) -> Result<()> {
+        let slot = &self.slots[index];
+        while slot.completed.load(Ordering::Acquire) == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TransportError::Timeout);
+            }
+            let tv = to_timeval(deadline - now);
+            let code = unsafe {
+                libusb_handle_events_timeout_completed(self.context, &tv, slot.completed.as_ptr())
+            };
+            if (code < 0) && (code != LIBUSB_ERROR_INTERRUPTED) {
+                return Err(TransportError::Usb(usb_error(code)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the oldest transfer and makes it the one being read.
+    fn next_transfer(&mut self, deadline: Instant) -> Result<()> {
+        let index = self.next;
+        if !self.slots[index].submitted {
+            // An earlier transfer failed and the ring has stopped
+            return Err(TransportError::Disconnected);
+        }
+        self.wait_for(index, deadline)?;
+
+        let slot = &mut self.slots[index];
+        slot.submitted = false;
+        let (status, actual_length) = unsafe { ((*slot.transfer).status, (*slot.transfer).actual_length) };
+        if status != LIBUSB_TRANSFER_COMPLETED {
+            return Err(transfer_error(status));
+        }
+        self.current = Some(index);
+        self.current_len = actual_length as usize;
+        self.current_pos = 0;
+        self.next = (index + 1) % self.slots.len();
+        Ok(())
+    }
+
+    pub fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let deadline = Instant::now() + timeout;
+        // Zero length packets complete a transfer without any data
+        let index = loop {
+            match self.current {
+                Some(index) if self.current_pos < self.current_len => break index,
+                Some(index) => {
+                    self.current = None;
+                    self.submit(index)?;
+                }
+                None => self.next_transfer(deadline)?,
+            }
+        };
+        let len = cmp::min(buf.len(), self.current_len - self.current_pos);
+        buf[..len].copy_from_slice(&self.slots[index].buffer[self.current_pos..self.current_pos + len]);
+        self.current_pos += len;
+        Ok(len)
+    }
+}
+
+impl Drop for BulkIn {
+    fn drop(&mut self) {
+        for slot in &self.slots {
+            if slot.submitted {
+                unsafe { libusb_cancel_transfer(slot.transfer); }
+            }
+        }
+        // Cancelling is asynchronous too; libusb may write into the buffers
+        // until each transfer has come back
+        let deadline = Instant::now() + CANCEL_TIMEOUT;
+        for index in 0..self.slots.len() {
+            if self.slots[index].submitted {
+                let _ = self.wait_for(index, deadline);
+            }
+        }
+        for slot in self.slots.drain(..) {
+            if slot.submitted && (slot.completed.load(Ordering::Acquire) == 0) {
+                println!("Bulk transfer did not cancel, leaking it");
+                std::mem::forget(slot);
+                continue;
+            }
+            unsafe { libusb_free_transfer(slot.transfer); }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! # Message Writer for CarPlay Client
+//!
+//! Owns the outgoing side of the transport. Messages are queued by priority
+//! and sent from a dedicated thread, so a large file transfer never holds up
+//! a touch event. A `Heartbeat` only goes out when nothing at all has been
+//! sent for `HEARTBEAT_INTERVAL`; any other message keeps the box awake just
+//! as well.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::box_protocol::*;
+use super::capture::{Capture, Direction};
+use super::recording::Recorder;
+use super::stats::LinkStats;
+use super::transport::{Transport, TransportError};
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const TX_TIMEOUT: Duration = Duration::from_secs(1);
+/// Writes timing out this many times in a row mean the box is gone rather
+/// than just busy.
+const MAX_TIMEOUTS: u32 = 5;
+
+/// Order in which queued messages leave; higher goes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Heartbeat,
+    /// Bulk transfers that can trickle out whenever the link is quiet.
+    Background,
+    Normal,
+    /// User input and session control.
+    Control,
+}
+
+impl Priority {
+    pub fn of(msg: &MsgType) -> Priority {
+        match msg {
+            MsgType::Touch(_) | MsgType::MultiTouch(_) | MsgType::ButtonCtl(_) |
+            MsgType::OpenBox(_) | MsgType::CloseBox(_) |
+            MsgType::DevPlug(_) | MsgType::DevUnplug(_) => Priority::Control,
+            MsgType::SendFile(_) => Priority::Background,
+            MsgType::Heartbeat(_) => Priority::Heartbeat,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+struct Queued {
+    priority: Priority,
+    seq: u64,
+    msg: MsgType,
+}
+
+// Highest priority first, then first in first out
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+struct Queue {
+    heap: BinaryHeap<Queued>,
+    next_seq: u64,
+    stopped: bool,
+    /// Stop once the queue is empty rather than right away.
+    draining: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    wakeup: Condvar,
+}
+
+/// Cheap handle for queueing messages on a running `Writer`.
+#[derive(Clone)]
+pub struct WriterHandle {
+    shared: Arc<Shared>,
+}
+
+impl WriterHandle {
+    pub fn send(&self, msg: MsgType) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.heap.push(Queued { priority: Priority::of(&msg), seq, msg });
+        self.shared.wakeup.notify_one();
+    }
+}
+
+pub struct Writer {
+    handle: WriterHandle,
+    thread: Option<JoinHandle<()>>,
+    errors: Receiver<TransportError>,
+}
+
+impl Writer {
+    pub fn spawn(transport: Arc<dyn Transport>, stats: LinkStats, capture: Capture, recorder: Recorder) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue { heap: BinaryHeap::new(), next_seq: 0, stopped: false, draining: false }),
+            wakeup: Condvar::new(),
+        });
+        let (errors_tx, errors) = mpsc::channel();
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || write_loop(&*transport, &thread_shared, &stats, &capture, &recorder, errors_tx));
+        Self { handle: WriterHandle { shared }, thread: Some(thread), errors }
+    }
+    pub fn handle(&self) -> WriterHandle {
+        self.handle.clone()
+    }
+    pub fn send(&self, msg: MsgType) {
+        self.handle.send(msg)
+    }
+    /// Sends everything still queued, then stops the writer thread.
+    pub fn finish(mut self) {
+        self.handle.shared.queue.lock().unwrap().draining = true;
+        self.handle.shared.wakeup.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+    /// Returns the error that stopped the writer thread, if it has stopped.
+    pub fn error(&self) -> Option<TransportError> {
+        self.errors.try_recv().ok()
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.handle.shared.queue.lock().unwrap().stopped = true;
+        self.handle.shared.wakeup.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Waits for the next message to send; `None` once the writer is stopped.
+fn next_msg(shared: &Shared, last_sent: Instant) -> Option<MsgType> {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        if queue.stopped {
+            return None;
+        }
+        if let Some(queued) = queue.heap.pop() {
+            return Some(queued.msg);
+        }
+        if queue.draining {
+            return None;
+        }
+        let due = last_sent + HEARTBEAT_INTERVAL;
+        let now = Instant::now();
+        if now >= due {
+            return Some(MsgType::Heartbeat(Heartbeat::new()));
+        }
+        queue = shared.wakeup.wait_timeout(queue, due - now).unwrap().0;
+    }
+}
+
+/// Sends one frame, picking up where a timed out write left off, so a box
+/// that is slow to drain its endpoint doesn't end the session.
+fn send_frame(transport: &dyn Transport, shared: &Shared, stats: &LinkStats, frame: &[u8])
+              -> Result<usize, TransportError> {
+    let mut written = 0;
+    let mut timeouts = 0;
+    while written < frame.len() {
+        match transport.write(&frame[written..], TX_TIMEOUT) {
+            Ok(len) => {
+                written += len;
+                timeouts = 0;
+            }
+            Err(TransportError::Timeout) => {
+                stats.timeout();
+                timeouts += 1;
+                if (timeouts == MAX_TIMEOUTS) || shared.queue.lock().unwrap().stopped {
+                    return Err(TransportError::Timeout);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(written)
+}
+
+fn write_loop(transport: &dyn Transport, shared: &Shared, stats: &LinkStats, capture: &Capture,
+              recorder: &Recorder, errors: Sender<TransportError>) {
+    let mut last_sent = Instant::now();
+    while let Some(msg) = next_msg(shared, last_sent) {
+        let frame = msg.serialize();
+        capture.record(Direction::Out, &frame);
+        recorder.record(Direction::Out, &frame);
+        match send_frame(transport, shared, stats, &frame) {
+            Ok(len) => {
+                last_sent = Instant::now();
+                stats.sent(msg.msg_type(), len);
+            }
+            Err(e) => {
+                let _ = errors.send(e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link_layer::buffer_pool::BufferPool;
+    use crate::link_layer::transport;
+
+    /// Times out a given number of times, then takes everything.
+    struct StalledTransport {
+        timeouts: Mutex<u32>,
+        written: Mutex<Vec<u8>>,
+    }
+
+    impl Transport for StalledTransport {
+        fn read(&self, _buf: &mut [u8], _timeout: Duration) -> transport::Result<usize> {
+            Err(TransportError::Timeout)
+        }
+        fn write(&self, buf: &[u8], _timeout: Duration) -> transport::Result<usize> {
+            let mut timeouts = self.timeouts.lock().unwrap();
+            if *timeouts > 0 {
+                *timeouts -= 1;
+                return Err(TransportError::Timeout);
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn send_through(timeouts: u32) -> (Arc<StalledTransport>, Option<TransportError>) {
+        let transport = Arc::new(StalledTransport { timeouts: Mutex::new(timeouts), written: Mutex::new(Vec::new()) });
+        let writer = Writer::spawn(transport.clone(), LinkStats::new(BufferPool::default()), Capture::new(0),
+                                   Recorder::new());
+        writer.send(MsgType::ButtonCtl(ButtonCtl::new(BUTTON_USE_BOX_MIC)));
+        writer.send(MsgType::Heartbeat(Heartbeat::new()));
+        thread::sleep(Duration::from_millis(50));
+        let error = writer.error();
+        writer.finish();
+        (transport, error)
+    }
+
+    #[test]
+    fn timeouts_are_retried() {
+        let (transport, error) = send_through(MAX_TIMEOUTS - 1);
+        assert!(error.is_none());
+        let expected = [MsgType::ButtonCtl(ButtonCtl::new(BUTTON_USE_BOX_MIC)).serialize(),
+                        MsgType::Heartbeat(Heartbeat::new()).serialize()].concat();
+        assert_eq!(*transport.written.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn persistent_timeouts_end_the_writer() {
+        let (transport, error) = send_through(MAX_TIMEOUTS);
+        match error {
+            Some(TransportError::Timeout) => (),
+            _ => panic!("expected the writer to give up"),
+        }
+        assert!(transport.written.lock().unwrap().is_empty());
+    }
+}
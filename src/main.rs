@@ -1,27 +1,64 @@
 use std::sync::mpsc;
+use std::process;
 // use std::thread;
 // use std::time::Duration;
 
-mod input_layer;
-mod link_layer;
-mod player_layer;
+use carplay_client::{config, input_layer, link_layer, player_layer, signal};
 
 // use crate::input_layer as imported_input_layer;
 // use crate::link_layer as imported_link_layer;
 
 fn main() {
+    let config = match config::Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("carplay-client: {}\n\n{}", e, config::USAGE);
+            process::exit(2);
+        }
+    };
+    if config.help {
+        println!("{}", config::USAGE);
+        return;
+    }
+    if config.list_devices {
+        if let Err(e) = link_layer::list_devices(&config.link.device) {
+            eprintln!("carplay-client: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = signal::install() {
+        eprintln!("carplay-client: could not install signal handlers: {}", e);
+        process::exit(1);
+    }
+
     let (tx_input, rx_input) = mpsc::channel();
     let (tx_player, rx_player) = mpsc::channel();
+    let link_thread_handle = match link_layer::link_thread(config.link, tx_player, rx_input) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("carplay-client: {}", e);
+            process::exit(1);
+        }
+    };
+    // The player asks the link for keyframes through the input channel too
+    let player_thread_handle = player_layer::player_thread(rx_player, tx_input.clone(), config.player);
     let input_thread_handle = input_layer::input_thread(tx_input);
-    let _link_thread_handle = link_layer::link_thread(tx_player, rx_input);
-    let _player_thread_handle = player_layer::player_thread(rx_player);
-
-    // let link_object = imported_link_layer::LinkLayer::new(tx_player, rx_input);
-    // for i in 1..5 {
-    //     println!("hi number {} from the main thread!", i);
-    //     thread::sleep(Duration::from_millis(1));
-    // }
 
-    input_thread_handle.join().unwrap(); // Stays for now; we need to find a better way
-    // to wait on other threads (mainly more logical)
-}
\ No newline at end of file
+    // The link only returns once shutdown was requested, or by panicking;
+    // either way everything else stops with it
+    let mut status = 0;
+    if link_thread_handle.join().is_err() {
+        status = 1;
+    }
+    signal::request_shutdown();
+    // The player goes once the link has dropped its end of the channel
+    for handle in [player_thread_handle, input_thread_handle] {
+        if handle.join().is_err() {
+            status = 1;
+        }
+    }
+    println!("Shut down");
+    process::exit(status);
+}
@@ -1,21 +1,249 @@
 //! # Player Layer for CarPlay Client
-//! 
-//! Decodes and displays CarPlay interface after receiving the appropriate 
+//!
+//! Decodes and displays CarPlay interface after receiving the appropriate
 //! serialized packets from the AutoBox Server hardware.
 
-use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
 
-// extern crate mpv;
-// use mpv::*;
+use crate::config::DisplayConfig;
+use crate::link_layer::{LinkCommand, LinkEvent};
+use crate::link_layer::box_protocol::*;
 
-// This module is empty for the time being; minimum feature validation
-// needs to be performed before this module will be populated.
+pub mod video_queue;
+use video_queue::{Frame, VideoQueue};
 
-// pub struct PlayerLayer {
-// }
+pub mod mpv_player;
 
-pub fn player_thread(_rx: Receiver<()>) -> std::thread::JoinHandle<()> {
+pub mod video_sink;
+use video_sink::{SinkKind, StreamStats};
+
+pub mod frame_output;
+pub mod kms;
+
+pub mod audio_engine;
+use audio_engine::{AudioConfig, AudioEngine};
+pub mod audio_output;
+pub mod ducking;
+pub mod jitter_buffer;
+pub mod playback_tap;
+use playback_tap::PlaybackTap;
+pub mod resampler;
+use kms::DrmConfig;
+#[cfg(feature = "openh264")]
+pub mod openh264;
+
+/// Phones take a moment to produce a keyframe; asking again sooner than
+/// this only adds load.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much audio is mixed at a time.
+const AUDIO_PERIOD: Duration = Duration::from_millis(10);
+
+/// How often the `png` video sink saves a snapshot, unless configured.
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct PlayerConfig {
+    pub display: DisplayConfig,
+    pub sink: SinkKind,
+    pub snapshot_interval: Duration,
+    /// mpv video output to use instead of its default, such as `drm`.
+    pub mpv_vo: Option<String>,
+    /// Output for the `kms` sink, and for mpv when it uses `drm`.
+    pub drm: DrmConfig,
+    pub audio: AudioConfig,
+    /// Gets a copy of everything played, for echo cancellation.
+    pub playback_tap: Option<PlaybackTap>,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        Self {
+            display: DisplayConfig::default(),
+            sink: SinkKind::default(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            mpv_vo: None,
+            drm: DrmConfig::default(),
+            audio: AudioConfig::default(),
+            playback_tap: None,
+        }
+    }
+}
+
+pub struct PlayerLayer {
+    video_queue: Arc<VideoQueue>,
+    video_thread: Option<thread::JoinHandle<()>>,
+    link_layer_tx: Sender<LinkCommand>,
+    last_keyframe_request: Option<Instant>,
+    /// Kept up to date by the video thread, for sinks that look at the stream.
+    stream_stats: Arc<Mutex<Option<StreamStats>>>,
+    audio_engine: Arc<Mutex<AudioEngine>>,
+    audio_thread: Option<thread::JoinHandle<()>>,
+    audio_running: Arc<AtomicBool>,
+}
+
+impl PlayerLayer {
+    pub fn new(config: PlayerConfig, link_layer_tx: Sender<LinkCommand>) -> Self {
+        let video_queue = Arc::new(VideoQueue::new(video_queue::DEFAULT_CAPACITY));
+        let queue = video_queue.clone();
+        let stream_stats = Arc::new(Mutex::new(None));
+        let thread_stats = stream_stats.clone();
+        let (audio_config, playback_tap) = (config.audio.clone(), config.playback_tap.clone());
+        let video_thread = thread::spawn(move || video_loop(&queue, &config, &thread_stats));
+
+        let audio_engine = Arc::new(Mutex::new(AudioEngine::new(audio_config.sample_rate,
+                                                                audio_engine::OUTPUT_CHANNELS,
+                                                                audio_config.target_latency,
+                                                                audio_config.ducking)));
+        let audio_running = Arc::new(AtomicBool::new(true));
+        let (engine, running) = (audio_engine.clone(), audio_running.clone());
+        let audio_thread = thread::spawn(move || {
+            audio_loop(&engine, &audio_config, playback_tap.as_ref(), &running)
+        });
+        Self {
+            video_queue,
+            video_thread: Some(video_thread),
+            link_layer_tx,
+            last_keyframe_request: None,
+            stream_stats,
+            audio_engine,
+            audio_thread: Some(audio_thread),
+            audio_running,
+        }
+    }
+
+    /// What the video sink made of the stream so far; `None` unless it is
+    /// the null sink.
+    pub fn stream_stats(&self) -> Option<StreamStats> {
+        *self.stream_stats.lock().unwrap()
+    }
+
+    pub fn handle_event(&mut self, event: LinkEvent) {
+        match event {
+            LinkEvent::StateChanged(state) => println!("Link is now {:?}", state),
+            LinkEvent::Message(MsgType::Video(video)) => {
+                if self.video_queue.push(Frame::from_video(&video)) {
+                    self.request_keyframe();
+                }
+            }
+            LinkEvent::Message(MsgType::Audio(audio)) => self.audio_engine.lock().unwrap().push(&audio),
+            LinkEvent::Message(_) => {},
+            LinkEvent::Stats(stats) => {
+                println!("Link stats: {}", stats);
+                self.print_audio_stats();
+            }
+        }
+    }
+
+    fn print_audio_stats(&self) {
+        for (audio_type, stats) in self.audio_engine.lock().unwrap().stats() {
+            println!("Audio stream {}: {}", audio_type, stats);
+        }
+    }
+
+    fn request_keyframe(&mut self) {
+        if let Some(last) = self.last_keyframe_request {
+            if last.elapsed() < KEYFRAME_REQUEST_INTERVAL {
+                return;
+            }
+        }
+        println!("Video fell behind ({:?}), requesting a keyframe", self.video_queue.stats());
+        self.last_keyframe_request = Some(Instant::now());
+        let request = MsgType::ButtonCtl(ButtonCtl::new(BUTTON_REQUEST_KEYFRAME));
+        let _ = self.link_layer_tx.send(LinkCommand::Send(request));
+    }
+}
+
+impl Drop for PlayerLayer {
+    fn drop(&mut self) {
+        self.video_queue.close();
+        if let Some(thread) = self.video_thread.take() {
+            let _ = thread.join();
+        }
+        self.audio_running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.audio_thread.take() {
+            let _ = thread.join();
+        }
+        if let Some(stats) = self.stream_stats() {
+            println!("Video received: {}", stats);
+        }
+        self.print_audio_stats();
+    }
+}
+
+/// Feeds frames to the video sink, which has to be set up on this thread.
+/// Without one, frames are still taken off the queue so the link keeps
+/// flowing.
+fn video_loop(queue: &VideoQueue, config: &PlayerConfig, stream_stats: &Mutex<Option<StreamStats>>) {
+    let mut sink = match config.sink.open(config) {
+        Ok(sink) => Some(sink),
+        Err(e) => {
+            println!("Video will not be shown: {}", e);
+            None
+        }
+    };
+    while let Some(frame) = queue.pop() {
+        if let Some(video) = sink.as_mut() {
+            if let Err(e) = video.show(&frame) {
+                println!("Video stopped: {}", e);
+                sink = None;
+                continue;
+            }
+            *stream_stats.lock().unwrap() = video.stream_stats();
+        }
+    }
+}
+
+/// Mixes a period at a time into the audio output, which has to be set up
+/// on this thread. Outputs that don't keep time are paced by the clock.
+fn audio_loop(engine: &Mutex<AudioEngine>, config: &AudioConfig, tap: Option<&PlaybackTap>,
+              running: &AtomicBool) {
+    let (sample_rate, channels) = {
+        let engine = engine.lock().unwrap();
+        (engine.sample_rate(), engine.channels())
+    };
+    let mut output = match config.output.open(sample_rate, channels) {
+        Ok(output) => output,
+        Err(e) => {
+            println!("Audio will not be played: {}", e);
+            return;
+        }
+    };
+    let frames = sample_rate as usize * AUDIO_PERIOD.as_millis() as usize / 1000;
+    let mut period = vec![0i16; frames * channels as usize];
+    let mut next = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        engine.lock().unwrap().mix(&mut period);
+        if let Err(e) = output.write(&period) {
+            println!("Audio stopped: {}", e);
+            return;
+        }
+        if let Some(tap) = tap {
+            tap.write(&period, sample_rate, channels);
+        }
+        if !output.is_clocked() {
+            next += AUDIO_PERIOD;
+            let now = Instant::now();
+            match next.checked_duration_since(now) {
+                Some(wait) => thread::sleep(wait),
+                // Fell far behind; don't try to catch up all at once
+                None if now - next > AUDIO_PERIOD * 10 => next = now,
+                None => {},
+            }
+        }
+    }
+}
+
+pub fn player_thread(rx: Receiver<LinkEvent>, link_layer_tx: Sender<LinkCommand>,
+                     config: PlayerConfig) -> std::thread::JoinHandle<()> {
     thread::spawn(move|| {
+        let mut player_layer = PlayerLayer::new(config, link_layer_tx);
+        for event in rx {
+            player_layer.handle_event(event);
+        }
     })
-}
\ No newline at end of file
+}
@@ -0,0 +1,197 @@
+//! # Audio Engine for CarPlay Client
+//!
+//! The box multiplexes media, navigation, Siri and phone audio over one
+//! link, telling them apart by `audio_type`, and each can arrive in its own
+//! format. The engine keeps one stream per audio type, converts everything
+//! to the output's format as it comes in, and mixes whatever is buffered
+//! whenever the output asks for more. Each stream plays out of a
+//! `JitterBuffer`, and media is ducked under the others as `ducking`
+//! decides.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::link_layer::box_protocol::{audio_format, Audio, AudioCommand, AUDIO_TYPE_MEDIA};
+use super::audio_output::OutputKind;
+use super::ducking::{Ducker, DuckingConfig};
+use super::jitter_buffer::{self, JitterBuffer, JitterStats};
+use super::resampler::Resampler;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+pub const OUTPUT_CHANNELS: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioConfig {
+    pub output: OutputKind,
+    pub sample_rate: u32,
+    /// How far each stream is kept ahead of the output.
+    pub target_latency: Duration,
+    pub ducking: DuckingConfig,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            output: OutputKind::default(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            target_latency: jitter_buffer::DEFAULT_TARGET_LATENCY,
+            ducking: DuckingConfig::default(),
+        }
+    }
+}
+
+struct AudioStream {
+    decode_type: u32,
+    resampler: Resampler,
+    buffer: JitterBuffer,
+}
+
+pub struct AudioEngine {
+    sample_rate: u32,
+    channels: u16,
+    target_latency: Duration,
+    streams: BTreeMap<u32, AudioStream>,
+    ducker: Ducker,
+    /// Reused for each packet's samples, as sent and once converted.
+    decoded: Vec<i16>,
+    converted: Vec<i16>,
+    /// Reused for each period's mix and media gains, so mixing doesn't
+    /// allocate on the output thread.
+    sum: Vec<i32>,
+    media_gains: Vec<f32>,
+}
+
+impl AudioEngine {
+    pub fn new(sample_rate: u32, channels: u16, target_latency: Duration, ducking: DuckingConfig) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            target_latency,
+            streams: BTreeMap::new(),
+            ducker: Ducker::new(ducking, sample_rate),
+            decoded: Vec::new(),
+            converted: Vec::new(),
+            sum: Vec::new(),
+            media_gains: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Takes an `Audio` message from the box, samples or a command.
+    pub fn push(&mut self, audio: &Audio) {
+        if let Some(command) = audio.command() {
+            self.ducker.handle(command);
+            if is_stop(command) {
+                if let Some(stream) = self.streams.get_mut(&audio.audio_type()) {
+                    stream.buffer.end();
+                }
+            }
+            return;
+        }
+        let samples = match audio.samples() {
+            Some(samples) => samples,
+            None => return,
+        };
+        let (rate, channels) = match audio_format(audio.decode_type()) {
+            Some(format) => format,
+            None => {
+                println!("Ignoring audio with unknown decode type {}", audio.decode_type());
+                return;
+            }
+        };
+        let (out_rate, out_channels, target_latency) = (self.sample_rate, self.channels, self.target_latency);
+        let stream = self.streams.entry(audio.audio_type()).or_insert_with(|| {
+            println!("Audio stream {} started at {} Hz, {} channels", audio.audio_type(), rate, channels);
+            AudioStream {
+                decode_type: audio.decode_type(),
+                resampler: Resampler::new(rate, channels, out_rate, out_channels),
+                buffer: JitterBuffer::new(target_latency, out_rate, out_channels),
+            }
+        });
+        if stream.decode_type != audio.decode_type() {
+            println!("Audio stream {} now at {} Hz, {} channels", audio.audio_type(), rate, channels);
+            stream.decode_type = audio.decode_type();
+            stream.resampler = Resampler::new(rate, channels, out_rate, out_channels);
+        }
+
+        self.decoded.clear();
+        self.decoded.extend(samples.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])));
+        self.converted.clear();
+        let ratio = stream.resampler.ratio() * (1.0 + stream.buffer.rate_correction());
+        stream.resampler.process_with_ratio(&self.decoded, ratio, &mut self.converted);
+        stream.buffer.push(&self.converted);
+    }
+
+    /// How each stream's buffer is doing, by audio type.
+    pub fn stats(&self) -> Vec<(u32, JitterStats)> {
+        self.streams.iter().map(|(&audio_type, stream)| (audio_type, stream.buffer.stats())).collect()
+    }
+
+    /// Fills `out`, interleaved in the output format, with the sum of every
+    /// stream. Streams that run short are padded with silence.
+    pub fn mix(&mut self, out: &mut [i16]) {
+        let channels = self.channels as usize;
+        self.sum.clear();
+        self.sum.resize(out.len(), 0);
+        // The ramp runs on output time, whether there is media or not
+        let media_paused = self.ducker.is_paused();
+        self.media_gains.clear();
+        for _ in 0..out.len() / channels {
+            self.media_gains.push(self.ducker.next_gain());
+        }
+        for (&audio_type, stream) in self.streams.iter_mut() {
+            let is_media = audio_type == AUDIO_TYPE_MEDIA;
+            if is_media {
+                stream.buffer.hold(media_paused);
+                if media_paused {
+                    continue;
+                }
+            }
+            for (index, (total, sample)) in self.sum.iter_mut().zip(stream.buffer.pull(out.len())).enumerate() {
+                *total += match is_media {
+                    true => (sample as f32 * self.media_gains[index / channels]) as i32,
+                    false => sample as i32,
+                };
+            }
+        }
+        for (sample, &total) in out.iter_mut().zip(&self.sum) {
+            *sample = total.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+    }
+}
+
+fn is_stop(command: AudioCommand) -> bool {
+    use AudioCommand::*;
+    matches!(command, OutputStop | PhoneCallStop | NaviStop | SiriStop | MediaStop | AlertStop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 48 kHz stereo, as the output runs.
+    const DECODE_TYPE: u32 = 4;
+
+    fn packet(audio_type: u32, sample: i16, frames: usize) -> Audio {
+        Audio::new(DECODE_TYPE, audio_type, sample.to_le_bytes().repeat(frames * 2))
+    }
+
+    #[test]
+    fn mix_clamps() {
+        for &sample in &[i16::MAX, i16::MIN] {
+            let mut engine = AudioEngine::new(48000, 2, Duration::from_millis(10), DuckingConfig::default());
+            engine.push(&packet(AUDIO_TYPE_MEDIA, sample, 960));
+            engine.push(&packet(2, sample, 960));
+            let mut out = [0; 480];
+            engine.mix(&mut out);
+            assert!(out.iter().all(|&mixed| mixed == sample));
+        }
+    }
+}
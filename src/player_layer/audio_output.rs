@@ -0,0 +1,145 @@
+//! # Audio Outputs for CarPlay Client
+//!
+//! Where the mixed audio goes. `alsa` and `pulse` play it, when built with
+//! the feature of the same name; `wav` writes it to a file and `null`
+//! throws it away, which is what tests and machines without sound use.
+
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "alsa")]
+use crate::alsa;
+#[cfg(feature = "pulse")]
+use crate::pulse;
+use crate::wav::{WavFormat, WavWriter};
+
+/// How much audio the sound server or device is asked to hold. Enough to
+/// ride out a busy moment on the audio thread without adding noticeable
+/// delay to speech.
+pub const OUTPUT_LATENCY: Duration = Duration::from_millis(60);
+
+/// Takes interleaved samples in the format the output was opened with.
+pub trait AudioOutput {
+    /// Blocks while the output is full, if it plays in real time.
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+    /// Whether `write` keeps time by itself. Outputs that don't are paced
+    /// by whoever feeds them.
+    fn is_clocked(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputKind {
+    /// An ALSA device name.
+    Alsa(String),
+    /// A PulseAudio sink, or the default one.
+    Pulse(Option<String>),
+    Wav(PathBuf),
+    Null,
+}
+
+impl Default for OutputKind {
+    /// Whichever sound system the build has, ALSA first.
+    fn default() -> Self {
+        if cfg!(feature = "alsa") {
+            OutputKind::Alsa("default".into())
+        } else if cfg!(feature = "pulse") {
+            OutputKind::Pulse(None)
+        } else {
+            OutputKind::Null
+        }
+    }
+}
+
+impl OutputKind {
+    /// `alsa[:DEVICE]`, `pulse[:SINK]`, `wav:PATH` or `null`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (kind, argument) = match value.split_once(':') {
+            Some((kind, argument)) => (kind, Some(argument).filter(|argument| !argument.is_empty())),
+            None => (value, None),
+        };
+        let built = |output, built: bool| match built {
+            true => Ok(output),
+            false => Err(format!("the {} audio output needs a build with the {} feature", kind, kind)),
+        };
+        match (kind, argument) {
+            ("alsa", device) => built(OutputKind::Alsa(device.unwrap_or("default").into()), cfg!(feature = "alsa")),
+            ("pulse", sink) => built(OutputKind::Pulse(sink.map(String::from)), cfg!(feature = "pulse")),
+            ("wav", Some(path)) => Ok(OutputKind::Wav(path.into())),
+            ("null", None) => Ok(OutputKind::Null),
+            _ => Err(format!("unknown audio output `{}`, expected alsa[:DEVICE], pulse[:SINK], wav:PATH or null", value)),
+        }
+    }
+
+    /// Outputs may not be `Send`; open them on the thread that feeds them.
+    pub fn open(&self, sample_rate: u32, channels: u16) -> io::Result<Box<dyn AudioOutput>> {
+        Ok(match self {
+            #[cfg(feature = "alsa")]
+            OutputKind::Alsa(device) => {
+                println!("Playing audio on ALSA device {}", device);
+                Box::new(alsa::Pcm::open_playback(device, sample_rate, channels, OUTPUT_LATENCY)?)
+            }
+            #[cfg(feature = "pulse")]
+            OutputKind::Pulse(sink) => {
+                println!("Playing audio on PulseAudio sink {}", sink.as_deref().unwrap_or("(default)"));
+                Box::new(pulse::Simple::open_playback(sink.as_deref(), "CarPlay", sample_rate, channels,
+                                                      OUTPUT_LATENCY)?)
+            }
+            OutputKind::Wav(path) => {
+                println!("Writing audio to {}", path.display());
+                Box::new(WavOutput(WavWriter::create(path, WavFormat { sample_rate, channels })?))
+            }
+            OutputKind::Null => Box::new(NullOutput),
+            #[cfg(not(feature = "alsa"))]
+            OutputKind::Alsa(_) => return Err(unsupported("alsa")),
+            #[cfg(not(feature = "pulse"))]
+            OutputKind::Pulse(_) => return Err(unsupported("pulse")),
+        })
+    }
+}
+
+#[cfg(not(all(feature = "alsa", feature = "pulse")))]
+fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("built without the {} feature", feature))
+}
+
+#[cfg(feature = "alsa")]
+impl AudioOutput for alsa::Pcm {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        alsa::Pcm::write(self, samples)
+    }
+}
+
+#[cfg(feature = "pulse")]
+impl AudioOutput for pulse::Simple {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        pulse::Simple::write(self, samples)
+    }
+}
+
+/// Everything that was played, silence included, as a WAV file.
+pub struct WavOutput(WavWriter);
+
+impl AudioOutput for WavOutput {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        self.0.write(samples)
+    }
+
+    fn is_clocked(&self) -> bool {
+        false
+    }
+}
+
+pub struct NullOutput;
+
+impl AudioOutput for NullOutput {
+    fn write(&mut self, _samples: &[i16]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_clocked(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,187 @@
+//! # Ducking for CarPlay Client
+//!
+//! Keeps directions and Siri audible over music. The phone announces when
+//! navigation prompts, Siri and calls start and stop; while a prompt or
+//! Siri is on, media is turned down or paused, and during a call it is
+//! muted outright. Gain changes are ramped so they don't click.
+
+use std::time::Duration;
+
+use crate::link_layer::box_protocol::AudioCommand;
+
+pub const DEFAULT_DUCK_LEVEL_DB: f32 = -15.0;
+pub const DEFAULT_RAMP: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuckMode {
+    /// Play media this many dB quieter.
+    Lower(f32),
+    /// Hold media back until the others are done.
+    Pause,
+}
+
+impl DuckMode {
+    /// `pause`, or by how many dB to lower media, e.g. `12`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value == "pause" {
+            return Ok(DuckMode::Pause);
+        }
+        value.trim_end_matches("dB").parse::<f32>().ok().filter(|db| db.is_finite())
+             .map(|db| DuckMode::Lower(-db.abs()))
+             .ok_or(format!("expected pause or a level in dB, got `{}`", value))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DuckingConfig {
+    pub mode: DuckMode,
+    /// Time to go all the way between silent and full volume.
+    pub ramp: Duration,
+}
+
+impl Default for DuckingConfig {
+    fn default() -> Self {
+        Self { mode: DuckMode::Lower(DEFAULT_DUCK_LEVEL_DB), ramp: DEFAULT_RAMP }
+    }
+}
+
+/// Works out the gain for media, one output frame at a time.
+pub struct Ducker {
+    config: DuckingConfig,
+    navigation: bool,
+    siri: bool,
+    call: bool,
+    gain: f32,
+    /// Largest change in gain from one frame to the next.
+    step: f32,
+}
+
+impl Ducker {
+    pub fn new(config: DuckingConfig, sample_rate: u32) -> Self {
+        let ramp_frames = (config.ramp.as_secs_f32() * sample_rate as f32).max(1.0);
+        Self { config, navigation: false, siri: false, call: false, gain: 1.0, step: 1.0 / ramp_frames }
+    }
+
+    pub fn handle(&mut self, command: AudioCommand) {
+        let before = self.reason();
+        match command {
+            AudioCommand::NaviStart => self.navigation = true,
+            AudioCommand::NaviStop => self.navigation = false,
+            AudioCommand::SiriStart => self.siri = true,
+            AudioCommand::SiriStop => self.siri = false,
+            AudioCommand::PhoneCallStart => self.call = true,
+            AudioCommand::PhoneCallStop => self.call = false,
+            _ => return,
+        }
+        let after = self.reason();
+        if after != before {
+            match after {
+                Some(reason) => println!("Media audio ducked for {}", reason),
+                None => println!("Media audio back to full volume"),
+            }
+        }
+    }
+
+    fn reason(&self) -> Option<&'static str> {
+        match (self.call, self.siri, self.navigation) {
+            (true, _, _) => Some("a call"),
+            (_, true, _) => Some("Siri"),
+            (_, _, true) => Some("navigation"),
+            _ => None,
+        }
+    }
+
+    fn target(&self) -> f32 {
+        match (self.reason(), self.config.mode) {
+            (None, _) => 1.0,
+            (Some(_), _) if self.call => 0.0,
+            (Some(_), DuckMode::Lower(db)) => 10f32.powf(db / 20.0),
+            (Some(_), DuckMode::Pause) => 0.0,
+        }
+    }
+
+    /// Whether media should be held back instead of played. Only once it
+    /// has faded out, and never for a call, which mutes instead.
+    pub fn is_paused(&self) -> bool {
+        self.config.mode == DuckMode::Pause && !self.call && self.reason().is_some() && self.gain == 0.0
+    }
+
+    /// The gain for the next frame of media.
+    pub fn next_gain(&mut self) -> f32 {
+        let target = self.target();
+        // Finishing once less than half a step would be left keeps f32
+        // rounding from adding a frame to the ramp
+        self.gain = if (target - self.gain).abs() < self.step * 1.5 {
+            target
+        } else if self.gain < target {
+            self.gain + self.step
+        } else {
+            self.gain - self.step
+        };
+        self.gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 100 ms at 1 kHz, so a full ramp is 100 frames.
+    fn ducker(mode: DuckMode) -> Ducker {
+        Ducker::new(DuckingConfig { mode, ramp: Duration::from_millis(100) }, 1000)
+    }
+
+    fn run(ducker: &mut Ducker, frames: usize) -> f32 {
+        for _ in 1..frames {
+            ducker.next_gain();
+        }
+        ducker.next_gain()
+    }
+
+    #[test]
+    fn ramps_down_to_the_level() {
+        let mut ducker = ducker(DuckMode::Lower(-20.0));
+        ducker.handle(AudioCommand::NaviStart);
+        assert!(ducker.next_gain() > 0.98);
+        // 0.9 of the way down takes 90 frames
+        assert!(run(&mut ducker, 88) > 0.1);
+        assert_eq!(run(&mut ducker, 1), 0.1);
+        assert_eq!(run(&mut ducker, 10), 0.1);
+
+        ducker.handle(AudioCommand::NaviStop);
+        assert!(ducker.next_gain() < 0.12);
+        assert_eq!(run(&mut ducker, 89), 1.0);
+    }
+
+    #[test]
+    fn call_mutes() {
+        for &mode in &[DuckMode::Lower(-6.0), DuckMode::Pause] {
+            let mut ducker = ducker(mode);
+            ducker.handle(AudioCommand::NaviStart);
+            ducker.handle(AudioCommand::PhoneCallStart);
+            assert_eq!(run(&mut ducker, 100), 0.0);
+            assert!(!ducker.is_paused());
+        }
+    }
+
+    #[test]
+    fn pauses_once_faded_out() {
+        let mut ducker = ducker(DuckMode::Pause);
+        ducker.handle(AudioCommand::SiriStart);
+        assert!(!ducker.is_paused());
+        run(&mut ducker, 99);
+        assert!(!ducker.is_paused());
+        run(&mut ducker, 1);
+        assert!(ducker.is_paused());
+        ducker.handle(AudioCommand::SiriStop);
+        assert!(!ducker.is_paused());
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!(DuckMode::parse("12"), Ok(DuckMode::Lower(-12.0)));
+        assert_eq!(DuckMode::parse("-12dB"), Ok(DuckMode::Lower(-12.0)));
+        assert_eq!(DuckMode::parse("pause"), Ok(DuckMode::Pause));
+        assert!(DuckMode::parse("nan").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! # Decoded Frame Output for CarPlay Client
+//!
+//! Writes decoded pictures somewhere a person can look at them: a Y4M
+//! stream that plays in any video player, or a PNG snapshot every so often.
+//! Both are plain files, so they work on a box without a display or GPU.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// One decoded picture in planar 4:2:0, rows tightly packed.
+pub struct YuvFrame {
+    pub width: usize,
+    pub height: usize,
+    pub y: Vec<u8>,
+    /// Each chroma plane is half the width and half the height, rounded up.
+    pub u: Vec<u8>,
+    pub v: Vec<u8>,
+}
+
+impl YuvFrame {
+    pub fn chroma_width(&self) -> usize {
+        self.width.div_ceil(2)
+    }
+
+    /// The picture as 8 bit RGB.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        for row in 0..self.height {
+            for column in 0..self.width {
+                let (r, g, b) = self.rgb_at(column, row);
+                rgb.extend_from_slice(&[r, g, b]);
+            }
+        }
+        rgb
+    }
+
+    /// One pixel as 8 bit RGB, assuming BT.601 limited range like the
+    /// phones send.
+    pub fn rgb_at(&self, column: usize, row: usize) -> (u8, u8, u8) {
+        let chroma = (row / 2) * self.chroma_width() + column / 2;
+        let c = self.y[row * self.width + column] as i32 - 16;
+        let d = self.u[chroma] as i32 - 128;
+        let e = self.v[chroma] as i32 - 128;
+        let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+        (clamp(298 * c + 409 * e), clamp(298 * c - 100 * d - 208 * e), clamp(298 * c + 516 * d))
+    }
+}
+
+/// Somewhere decoded frames go.
+pub trait FrameOutput {
+    fn write(&mut self, frame: &YuvFrame) -> io::Result<()>;
+}
+
+/// A YUV4MPEG2 stream. Its header fixes the picture size, so frames of any
+/// other size are left out.
+pub struct Y4mWriter {
+    out: BufWriter<File>,
+    fps: u32,
+    size: Option<(usize, usize)>,
+}
+
+impl Y4mWriter {
+    pub fn create(path: &Path, fps: u32) -> io::Result<Self> {
+        println!("Writing decoded video to {}", path.display());
+        Ok(Self { out: BufWriter::new(File::create(path)?), fps, size: None })
+    }
+}
+
+impl FrameOutput for Y4mWriter {
+    fn write(&mut self, frame: &YuvFrame) -> io::Result<()> {
+        match self.size {
+            None => {
+                writeln!(self.out, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg",
+                         frame.width, frame.height, self.fps)?;
+                self.size = Some((frame.width, frame.height));
+            }
+            Some(size) if size != (frame.width, frame.height) => {
+                println!("Leaving out a {}x{} frame from a {}x{} Y4M stream",
+                         frame.width, frame.height, size.0, size.1);
+                return Ok(());
+            }
+            Some(_) => {},
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&frame.y)?;
+        self.out.write_all(&frame.u)?;
+        self.out.write_all(&frame.v)
+    }
+}
+
+/// Saves a frame as `snapshot-NNNNN.png` in a directory, at most once per
+/// interval.
+pub struct PngSnapshots {
+    dir: PathBuf,
+    interval: Duration,
+    last: Option<Instant>,
+    count: u32,
+}
+
+impl PngSnapshots {
+    pub fn create(dir: &Path, interval: Duration) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        println!("Saving a snapshot to {} every {:?}", dir.display(), interval);
+        Ok(Self { dir: dir.to_path_buf(), interval, last: None, count: 0 })
+    }
+}
+
+impl FrameOutput for PngSnapshots {
+    fn write(&mut self, frame: &YuvFrame) -> io::Result<()> {
+        if let Some(last) = self.last {
+            if last.elapsed() < self.interval {
+                return Ok(());
+            }
+        }
+        self.last = Some(Instant::now());
+        let path = self.dir.join(format!("snapshot-{:05}.png", self.count));
+        self.count += 1;
+        let png = encode_png(frame.width as u32, frame.height as u32, &frame.to_rgb());
+        fs::write(&path, png)
+    }
+}
+
+/// Encodes 8 bit RGB as a PNG. The image data is stored without
+/// compression, which keeps this short at the cost of file size.
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let row_len = width as usize * 3;
+    let mut raw = Vec::with_capacity((row_len + 1) * height as usize);
+    for row in rgb.chunks(row_len) {
+        raw.push(0); // no filter
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit RGB, not interlaced
+    put_chunk(&mut png, b"IHDR", &header);
+    put_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    put_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn put_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream made of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_ends_with_iend() {
+        let png = encode_png(1, 1, &[0x10, 0x80, 0xf0]);
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        assert_eq!(png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    }
+
+    #[test]
+    fn adler32_matches() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        // Long enough that the sums have to be reduced along the way
+        let data = vec![0xff; 100_000];
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in &data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        assert_eq!(adler32(&data), (b << 16) | a);
+    }
+
+    #[test]
+    fn stored_blocks_split_at_64k() {
+        let data = vec![7; 0xffff + 6];
+        let zlib = zlib_stored(&data);
+        assert_eq!(zlib.len(), 2 + (5 + 0xffff) + (5 + 6) + 4);
+        assert_eq!(zlib[2..7], [0, 0xff, 0xff, 0x00, 0x00]);
+        let second = 2 + 5 + 0xffff;
+        assert_eq!(zlib[second..second + 5], [1, 0x06, 0x00, 0xf9, 0xff]);
+        assert_eq!(zlib[zlib.len() - 4..], adler32(&data).to_be_bytes());
+    }
+}
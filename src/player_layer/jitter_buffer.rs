@@ -0,0 +1,249 @@
+//! # Audio Jitter Buffer for CarPlay Client
+//!
+//! USB hands audio over in bursts, the more so while video is busy, but the
+//! output takes it at a steady pace. Each stream keeps a cushion of audio
+//! ahead of the output and only starts playing once the cushion is there.
+//! The phone's clock and the sound card's never quite agree, so over a long
+//! drive the cushion would creep up or drain away; the stream is resampled
+//! a touch faster or slower to hold it at the target instead.
+
+use std::collections::vec_deque::Drain;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+pub const DEFAULT_TARGET_LATENCY: Duration = Duration::from_millis(80);
+
+/// Audio beyond the target a burst may bring before it counts as an
+/// overrun.
+const BURST_ALLOWANCE: Duration = Duration::from_millis(250);
+
+/// Largest change to the playback rate. Crystals stay within about
+/// 100 ppm of each other, and 1000 ppm is still too little to hear.
+const MAX_CORRECTION: f64 = 0.001;
+
+/// Rate correction per unit of relative error in the average fill.
+const CORRECTION_GAIN: f64 = 0.004;
+
+/// Errors in the average fill up to this fraction of the target are left
+/// alone. They come from how packets happen to line up with output
+/// periods, not from drift.
+const DEADBAND: f64 = 0.2;
+
+/// Weight of each new fill level in the running average; with 10 ms
+/// periods the average spans about a second.
+const FILL_SMOOTHING: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JitterStats {
+    pub buffered: Duration,
+    /// Times the stream ran dry while playing.
+    pub underruns: u64,
+    /// Times audio was dropped because too much had piled up.
+    pub overruns: u64,
+    /// Current rate correction, in parts per million.
+    pub correction_ppm: i32,
+}
+
+impl fmt::Display for JitterStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ms buffered, {} underruns, {} overruns, {:+} ppm",
+               self.buffered.as_millis(), self.underruns, self.overruns, self.correction_ppm)
+    }
+}
+
+pub struct JitterBuffer {
+    /// Interleaved, in the output format.
+    samples: VecDeque<i16>,
+    channels: usize,
+    sample_rate: u32,
+    /// Frames to have buffered before playing, and to hold on average.
+    target: usize,
+    /// Frames beyond which the buffer is cut back to `target`.
+    limit: usize,
+    playing: bool,
+    /// The phone said the stream stopped, so running dry is expected.
+    ending: bool,
+    /// The stream isn't being played for now, as when paused for ducking.
+    held: bool,
+    average_fill: f64,
+    underruns: u64,
+    overruns: u64,
+}
+
+impl JitterBuffer {
+    pub fn new(target_latency: Duration, sample_rate: u32, channels: u16) -> Self {
+        let frames = |duration: Duration| (duration.as_secs_f64() * sample_rate as f64) as usize;
+        let target = frames(target_latency);
+        Self {
+            samples: VecDeque::new(),
+            channels: channels as usize,
+            sample_rate,
+            target,
+            limit: target + frames(BURST_ALLOWANCE),
+            playing: false,
+            ending: false,
+            held: false,
+            average_fill: target as f64,
+            underruns: 0,
+            overruns: 0,
+        }
+    }
+
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    /// Cuts the buffer down to `frames`, oldest audio first.
+    fn trim_to(&mut self, frames: usize) {
+        let excess = self.frames().saturating_sub(frames) * self.channels;
+        self.samples.drain(..excess);
+    }
+
+    /// Interleaved samples in the output format.
+    pub fn push(&mut self, samples: &[i16]) {
+        self.samples.extend(samples);
+        self.ending = false;
+        if self.frames() > self.limit {
+            if self.held {
+                self.trim_to(self.limit);
+            } else {
+                self.trim_to(self.target);
+                self.overruns += 1;
+                self.average_fill = self.target as f64;
+            }
+        }
+    }
+
+    /// Up to `len` samples to play next. Fewer means the stream ran dry,
+    /// or hasn't built up its cushion yet.
+    pub fn pull(&mut self, len: usize) -> Drain<'_, i16> {
+        if !self.playing {
+            let ready = self.frames() >= self.target || (self.ending && !self.samples.is_empty());
+            if !ready {
+                return self.samples.drain(..0);
+            }
+            self.playing = true;
+            self.average_fill = self.frames() as f64;
+        }
+        self.average_fill += (self.frames() as f64 - self.average_fill) * FILL_SMOOTHING;
+        if self.samples.len() < len {
+            if !self.ending {
+                self.underruns += 1;
+            }
+            self.playing = false;
+            self.ending = false;
+        }
+        let available = len.min(self.samples.len());
+        self.samples.drain(..available)
+    }
+
+    /// The phone stopped the stream; play out what is left without
+    /// counting it as an underrun.
+    pub fn end(&mut self) {
+        self.ending = true;
+    }
+
+    pub fn hold(&mut self, held: bool) {
+        if self.held && !held {
+            // Start again from the newest audio, not with a backlog
+            self.trim_to(self.target);
+            self.average_fill = self.frames() as f64;
+        }
+        self.held = held;
+    }
+
+    /// How much to stretch the stream (above 0) or squeeze it (below 0)
+    /// when resampling, as a fraction of the nominal ratio.
+    pub fn rate_correction(&self) -> f64 {
+        if !self.playing || self.target == 0 {
+            return 0.0;
+        }
+        let error = (self.target as f64 - self.average_fill) / self.target as f64;
+        let beyond_deadband = error.signum() * (error.abs() - DEADBAND).max(0.0);
+        (beyond_deadband * CORRECTION_GAIN).clamp(-MAX_CORRECTION, MAX_CORRECTION)
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats {
+            buffered: Duration::from_secs_f64(self.frames() as f64 / self.sample_rate as f64),
+            underruns: self.underruns,
+            overruns: self.overruns,
+            correction_ppm: (self.rate_correction() * 1e6).round() as i32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One frame per millisecond, so the 80 ms target is 80 frames and
+    /// the limit 330.
+    fn buffer() -> JitterBuffer {
+        JitterBuffer::new(Duration::from_millis(80), 1000, 1)
+    }
+
+    #[test]
+    fn waits_for_the_target() {
+        let mut buffer = buffer();
+        buffer.push(&[1; 79]);
+        assert_eq!(buffer.pull(10).count(), 0);
+        buffer.push(&[1]);
+        assert_eq!(buffer.pull(10).count(), 10);
+        assert_eq!(buffer.stats().underruns, 0);
+    }
+
+    #[test]
+    fn underruns_only_while_the_stream_runs() {
+        let mut buffer = buffer();
+        buffer.push(&[1; 80]);
+        assert_eq!(buffer.pull(50).count(), 50);
+        assert_eq!(buffer.pull(50).count(), 30);
+        assert_eq!(buffer.stats().underruns, 1);
+        // Dry again, and waiting for the target before playing
+        assert_eq!(buffer.pull(50).count(), 0);
+
+        buffer.push(&[1; 80]);
+        assert_eq!(buffer.pull(50).count(), 50);
+        buffer.end();
+        assert_eq!(buffer.pull(50).count(), 30);
+        assert_eq!(buffer.stats().underruns, 1);
+    }
+
+    #[test]
+    fn overrun_trims_to_the_target() {
+        let mut buffer = buffer();
+        buffer.push(&[1; 330]);
+        assert_eq!(buffer.stats().overruns, 0);
+        buffer.push(&[2]);
+        let stats = buffer.stats();
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.buffered, Duration::from_millis(80));
+        // The newest audio is kept
+        assert_eq!(buffer.pull(80).next_back(), Some(2));
+    }
+
+    #[test]
+    fn rate_correction_follows_the_fill() {
+        let mut buffer = buffer();
+        buffer.push(&[0; 80]);
+        assert_eq!(buffer.rate_correction(), 0.0);
+        buffer.pull(1);
+        // Inside the deadband
+        assert_eq!(buffer.rate_correction(), 0.0);
+
+        // Running low, so stretch
+        buffer.average_fill = 56.0;
+        assert!((buffer.rate_correction() - 0.1 * CORRECTION_GAIN).abs() < 1e-9);
+        buffer.average_fill = 0.0;
+        assert_eq!(buffer.rate_correction(), MAX_CORRECTION);
+
+        // Piling up, so squeeze
+        buffer.average_fill = 104.0;
+        assert!((buffer.rate_correction() + 0.1 * CORRECTION_GAIN).abs() < 1e-9);
+        buffer.average_fill = 330.0;
+        assert_eq!(buffer.rate_correction(), -MAX_CORRECTION);
+        assert_eq!(buffer.stats().correction_ppm, -1000);
+    }
+}
@@ -0,0 +1,581 @@
+//! # DRM/KMS Output for CarPlay Client
+//!
+//! Puts decoded pictures straight on a screen through the kernel's mode
+//! setting interface, for head units that boot into the client with no X11
+//! or Wayland. Pictures are converted and scaled on the CPU into a pair of
+//! dumb buffers, which is slow but works with any driver, `vkms` included.
+//! The mode is set once; after that each picture is a page flip, shown at
+//! the next vertical blank.
+//!
+//! mpv's own `--vo=drm` is the fast path; this is the one to test with.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::{c_char, c_ulong};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::ptr;
+
+extern crate libc;
+
+use super::frame_output::{FrameOutput, YuvFrame};
+
+pub const DEFAULT_DEVICE: &str = "/dev/dri/card0";
+
+/// Which output to drive, and how. Anything left out is picked
+/// automatically: the first connected connector, a CRTC that can drive it
+/// and the connector's preferred mode.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DrmConfig {
+    pub device: Option<PathBuf>,
+    /// A connector name such as `HDMI-A-1`, or its id.
+    pub connector: Option<String>,
+    pub crtc: Option<u32>,
+    pub mode: Option<ModeRequest>,
+}
+
+impl DrmConfig {
+    pub fn device(&self) -> PathBuf {
+        self.device.clone().unwrap_or_else(|| DEFAULT_DEVICE.into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeRequest {
+    pub width: u16,
+    pub height: u16,
+    pub refresh: Option<u32>,
+}
+
+impl ModeRequest {
+    /// `WIDTHxHEIGHT`, optionally followed by `@HZ`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("expected a mode like 1920x720 or 1920x720@60, got `{}`", value);
+        let (size, refresh) = match value.split_once('@') {
+            Some((size, refresh)) => (size, Some(refresh.parse().map_err(|_| invalid())?)),
+            None => (value, None),
+        };
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        Ok(Self {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+            refresh,
+        })
+    }
+
+    fn matches(&self, mode: &ModeInfo) -> bool {
+        mode.hdisplay == self.width && mode.vdisplay == self.height &&
+            self.refresh.is_none_or(|refresh| mode.vrefresh == refresh)
+    }
+}
+
+// Kernel interface, from drm.h and drm_mode.h
+
+#[repr(C)]
+#[derive(Default)]
+struct CardRes {
+    fb_id_ptr: u64,
+    crtc_id_ptr: u64,
+    connector_id_ptr: u64,
+    encoder_id_ptr: u64,
+    count_fbs: u32,
+    count_crtcs: u32,
+    count_connectors: u32,
+    count_encoders: u32,
+    min_width: u32,
+    max_width: u32,
+    min_height: u32,
+    max_height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ModeInfo {
+    clock: u32,
+    hdisplay: u16,
+    hsync_start: u16,
+    hsync_end: u16,
+    htotal: u16,
+    hskew: u16,
+    vdisplay: u16,
+    vsync_start: u16,
+    vsync_end: u16,
+    vtotal: u16,
+    vscan: u16,
+    vrefresh: u32,
+    flags: u32,
+    mode_type: u32,
+    name: [c_char; 32],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct GetConnector {
+    encoders_ptr: u64,
+    modes_ptr: u64,
+    props_ptr: u64,
+    prop_values_ptr: u64,
+    count_modes: u32,
+    count_props: u32,
+    count_encoders: u32,
+    encoder_id: u32,
+    connector_id: u32,
+    connector_type: u32,
+    connector_type_id: u32,
+    connection: u32,
+    mm_width: u32,
+    mm_height: u32,
+    subpixel: u32,
+    pad: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct GetEncoder {
+    encoder_id: u32,
+    encoder_type: u32,
+    crtc_id: u32,
+    possible_crtcs: u32,
+    possible_clones: u32,
+}
+
+#[repr(C)]
+struct Crtc {
+    set_connectors_ptr: u64,
+    count_connectors: u32,
+    crtc_id: u32,
+    fb_id: u32,
+    x: u32,
+    y: u32,
+    gamma_size: u32,
+    mode_valid: u32,
+    mode: ModeInfo,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CreateDumb {
+    height: u32,
+    width: u32,
+    bpp: u32,
+    flags: u32,
+    handle: u32,
+    pitch: u32,
+    size: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapDumb {
+    handle: u32,
+    pad: u32,
+    offset: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct FbCmd {
+    fb_id: u32,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    bpp: u32,
+    depth: u32,
+    handle: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct PageFlip {
+    crtc_id: u32,
+    fb_id: u32,
+    flags: u32,
+    reserved: u32,
+    user_data: u64,
+}
+
+/// Header of each event read from the device.
+const EVENT_HEADER_LEN: usize = 8;
+const EVENT_FLIP_COMPLETE: u32 = 0x02;
+const PAGE_FLIP_EVENT: u32 = 0x01;
+/// A flip not done after this long won't be.
+const FLIP_TIMEOUT_MS: i32 = 1000;
+
+const fn iowr<T>(nr: c_ulong) -> c_ulong {
+    (3 << 30) | ((mem::size_of::<T>() as c_ulong) << 16) | ((b'd' as c_ulong) << 8) | nr
+}
+
+const IOCTL_MODE_GETRESOURCES: c_ulong = iowr::<CardRes>(0xA0);
+const IOCTL_MODE_GETCRTC: c_ulong = iowr::<Crtc>(0xA1);
+const IOCTL_MODE_SETCRTC: c_ulong = iowr::<Crtc>(0xA2);
+const IOCTL_MODE_GETENCODER: c_ulong = iowr::<GetEncoder>(0xA6);
+const IOCTL_MODE_GETCONNECTOR: c_ulong = iowr::<GetConnector>(0xA7);
+const IOCTL_MODE_ADDFB: c_ulong = iowr::<FbCmd>(0xAE);
+const IOCTL_MODE_RMFB: c_ulong = iowr::<u32>(0xAF);
+const IOCTL_MODE_CREATE_DUMB: c_ulong = iowr::<CreateDumb>(0xB2);
+const IOCTL_MODE_MAP_DUMB: c_ulong = iowr::<MapDumb>(0xB3);
+const IOCTL_MODE_PAGE_FLIP: c_ulong = iowr::<PageFlip>(0xB0);
+const IOCTL_MODE_DESTROY_DUMB: c_ulong = iowr::<u32>(0xB4);
+
+const MODE_CONNECTED: u32 = 1;
+const MODE_TYPE_PREFERRED: u32 = 1 << 3;
+
+/// Connector type names as the kernel prints them, by type number.
+const CONNECTOR_TYPES: &[&str] = &[
+    "Unknown", "VGA", "DVI-I", "DVI-D", "DVI-A", "Composite", "SVIDEO", "LVDS", "Component",
+    "DIN", "DP", "HDMI-A", "HDMI-B", "TV", "eDP", "Virtual", "DSI", "DPI", "Writeback", "SPI", "USB",
+];
+
+fn ioctl<T>(device: &File, request: c_ulong, arg: &mut T) -> io::Result<()> {
+    loop {
+        if unsafe { libc::ioctl(device.as_raw_fd(), request as _, arg as *mut T) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if !matches!(e.raw_os_error(), Some(libc::EINTR) | Some(libc::EAGAIN)) {
+            return Err(e);
+        }
+    }
+}
+
+fn not_found(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, what)
+}
+
+struct Connector {
+    id: u32,
+    name: String,
+    connected: bool,
+    encoder_id: u32,
+    encoders: Vec<u32>,
+    modes: Vec<ModeInfo>,
+}
+
+fn get_connector(device: &File, id: u32) -> io::Result<Connector> {
+    // Once for the counts, then again into buffers that fit them
+    let mut info = GetConnector { connector_id: id, ..Default::default() };
+    ioctl(device, IOCTL_MODE_GETCONNECTOR, &mut info)?;
+    let mut modes = vec![unsafe { mem::zeroed::<ModeInfo>() }; info.count_modes as usize];
+    let mut encoders = vec![0u32; info.count_encoders as usize];
+    let mut again = GetConnector {
+        connector_id: id,
+        modes_ptr: modes.as_mut_ptr() as u64,
+        count_modes: modes.len() as u32,
+        encoders_ptr: encoders.as_mut_ptr() as u64,
+        count_encoders: encoders.len() as u32,
+        ..Default::default()
+    };
+    ioctl(device, IOCTL_MODE_GETCONNECTOR, &mut again)?;
+    // A hotplug in between can change the counts; keep what was filled in
+    modes.truncate(again.count_modes.min(modes.len() as u32) as usize);
+    encoders.truncate(again.count_encoders.min(encoders.len() as u32) as usize);
+    let type_name = CONNECTOR_TYPES.get(again.connector_type as usize).unwrap_or(&"Unknown");
+    Ok(Connector {
+        id,
+        name: format!("{}-{}", type_name, again.connector_type_id),
+        connected: again.connection == MODE_CONNECTED,
+        encoder_id: again.encoder_id,
+        encoders,
+        modes,
+    })
+}
+
+struct DumbBuffer {
+    handle: u32,
+    fb_id: u32,
+    pitch: usize,
+    map: *mut u8,
+    size: usize,
+}
+
+impl DumbBuffer {
+    fn create(device: &File, width: u32, height: u32) -> io::Result<Self> {
+        let mut create = CreateDumb { width, height, bpp: 32, ..Default::default() };
+        ioctl(device, IOCTL_MODE_CREATE_DUMB, &mut create)?;
+        let mut buffer = Self {
+            handle: create.handle,
+            fb_id: 0,
+            pitch: create.pitch as usize,
+            map: ptr::null_mut(),
+            size: create.size as usize,
+        };
+        let mut fb = FbCmd {
+            width,
+            height,
+            pitch: create.pitch,
+            bpp: 32,
+            depth: 24,
+            handle: create.handle,
+            ..Default::default()
+        };
+        if let Err(e) = ioctl(device, IOCTL_MODE_ADDFB, &mut fb) {
+            buffer.destroy(device);
+            return Err(e);
+        }
+        buffer.fb_id = fb.fb_id;
+        let mut map = MapDumb { handle: create.handle, ..Default::default() };
+        let mapped = ioctl(device, IOCTL_MODE_MAP_DUMB, &mut map).and_then(|()| {
+            let address = unsafe {
+                libc::mmap(ptr::null_mut(), buffer.size, libc::PROT_READ | libc::PROT_WRITE,
+                           libc::MAP_SHARED, device.as_raw_fd(), map.offset as libc::off_t)
+            };
+            match address {
+                libc::MAP_FAILED => Err(io::Error::last_os_error()),
+                address => Ok(address as *mut u8),
+            }
+        });
+        match mapped {
+            Ok(address) => buffer.map = address,
+            Err(e) => {
+                buffer.destroy(device);
+                return Err(e);
+            }
+        }
+        // Start out black rather than with whatever was in memory
+        buffer.pixels().fill(0);
+        Ok(buffer)
+    }
+
+    fn pixels(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.map, self.size) }
+    }
+
+    fn destroy(&mut self, device: &File) {
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map as *mut libc::c_void, self.size) };
+            self.map = ptr::null_mut();
+        }
+        if self.fb_id != 0 {
+            let _ = ioctl(device, IOCTL_MODE_RMFB, &mut self.fb_id);
+        }
+        let _ = ioctl(device, IOCTL_MODE_DESTROY_DUMB, &mut self.handle);
+    }
+}
+
+/// A screen driven through two dumb buffers: one shown, one drawn into.
+pub struct KmsDisplay {
+    device: File,
+    connector_id: u32,
+    crtc_id: u32,
+    mode: ModeInfo,
+    buffers: [DumbBuffer; 2],
+    front: usize,
+    /// A flip was asked for and hasn't happened yet; until it has, the
+    /// buffer it leaves is still on screen.
+    flip_pending: bool,
+    /// What the CRTC showed before, put back when done.
+    saved: Option<Crtc>,
+}
+
+impl KmsDisplay {
+    pub fn open(config: &DrmConfig) -> io::Result<Self> {
+        let path = config.device();
+        let device = OpenOptions::new().read(true).write(true).open(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        let mut res = CardRes::default();
+        ioctl(&device, IOCTL_MODE_GETRESOURCES, &mut res)?;
+        let mut crtcs = vec![0u32; res.count_crtcs as usize];
+        let mut connectors = vec![0u32; res.count_connectors as usize];
+        let mut again = CardRes {
+            crtc_id_ptr: crtcs.as_mut_ptr() as u64,
+            count_crtcs: crtcs.len() as u32,
+            connector_id_ptr: connectors.as_mut_ptr() as u64,
+            count_connectors: connectors.len() as u32,
+            ..Default::default()
+        };
+        ioctl(&device, IOCTL_MODE_GETRESOURCES, &mut again)?;
+
+        let connector = pick_connector(&device, &connectors, config.connector.as_deref())?;
+        let mode = pick_mode(&connector, config.mode)?;
+        let crtc_id = match config.crtc {
+            Some(crtc) if crtcs.contains(&crtc) => crtc,
+            Some(crtc) => return Err(not_found(format!("there is no CRTC {}", crtc))),
+            None => pick_crtc(&device, &connector, &crtcs)?,
+        };
+
+        let (width, height) = (mode.hdisplay as u32, mode.vdisplay as u32);
+        let first = DumbBuffer::create(&device, width, height)?;
+        let second = match DumbBuffer::create(&device, width, height) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                let mut first = first;
+                first.destroy(&device);
+                return Err(e);
+            }
+        };
+        let mut saved = Crtc { crtc_id, ..unsafe { mem::zeroed() } };
+        let saved = ioctl(&device, IOCTL_MODE_GETCRTC, &mut saved).ok().map(|()| saved);
+        let mut display = Self {
+            device,
+            connector_id: connector.id,
+            crtc_id,
+            mode,
+            buffers: [first, second],
+            front: 0,
+            flip_pending: false,
+            saved,
+        };
+        display.set_mode()?;
+        println!("Showing video on {} at {}x{}@{} through CRTC {}",
+                 connector.name, width, height, mode.vrefresh, crtc_id);
+        Ok(display)
+    }
+
+    /// Lights up the output with the front buffer.
+    fn set_mode(&mut self) -> io::Result<()> {
+        let mut connector_id = self.connector_id;
+        let mut crtc = Crtc {
+            set_connectors_ptr: &mut connector_id as *mut u32 as u64,
+            count_connectors: 1,
+            crtc_id: self.crtc_id,
+            fb_id: self.buffers[self.front].fb_id,
+            x: 0,
+            y: 0,
+            gamma_size: 0,
+            mode_valid: 1,
+            mode: self.mode,
+        };
+        ioctl(&self.device, IOCTL_MODE_SETCRTC, &mut crtc)
+    }
+
+    /// Shows `index` from the next vertical blank on.
+    fn flip(&mut self, index: usize) -> io::Result<()> {
+        let mut flip = PageFlip {
+            crtc_id: self.crtc_id,
+            fb_id: self.buffers[index].fb_id,
+            flags: PAGE_FLIP_EVENT,
+            ..Default::default()
+        };
+        ioctl(&self.device, IOCTL_MODE_PAGE_FLIP, &mut flip)?;
+        self.flip_pending = true;
+        self.front = index;
+        Ok(())
+    }
+
+    /// Waits for the last flip to be done, after which the back buffer is
+    /// free to draw into.
+    fn wait_for_flip(&mut self) -> io::Result<()> {
+        let mut events = [0u8; 1024];
+        while self.flip_pending {
+            let mut pollfd = libc::pollfd { fd: self.device.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            match unsafe { libc::poll(&mut pollfd, 1, FLIP_TIMEOUT_MS) } {
+                0 => return Err(io::Error::new(io::ErrorKind::TimedOut, "page flip never completed")),
+                n if n < 0 => match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                },
+                _ => {},
+            }
+            let len = (&self.device).read(&mut events)?;
+            let mut offset = 0;
+            while offset + EVENT_HEADER_LEN <= len {
+                let field = |at: usize| u32::from_ne_bytes([events[at], events[at + 1], events[at + 2], events[at + 3]]);
+                let (event_type, event_len) = (field(offset), field(offset + 4) as usize);
+                if event_type == EVENT_FLIP_COMPLETE {
+                    self.flip_pending = false;
+                }
+                offset += event_len.max(EVENT_HEADER_LEN);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FrameOutput for KmsDisplay {
+    /// Scales the picture to the mode, nearest neighbour, and flips to it.
+    fn write(&mut self, frame: &YuvFrame) -> io::Result<()> {
+        self.wait_for_flip()?;
+        let back = 1 - self.front;
+        let (width, height) = (self.mode.hdisplay as usize, self.mode.vdisplay as usize);
+        let columns: Vec<usize> = (0..width).map(|x| x * frame.width / width).collect();
+        let buffer = &mut self.buffers[back];
+        let pitch = buffer.pitch;
+        let pixels = buffer.pixels();
+        for y in 0..height {
+            let source_row = y * frame.height / height;
+            let row = &mut pixels[y * pitch..y * pitch + width * 4];
+            for (pixel, &source_column) in row.chunks_exact_mut(4).zip(&columns) {
+                let (r, g, b) = frame.rgb_at(source_column, source_row);
+                pixel.copy_from_slice(&[b, g, r, 0]);
+            }
+        }
+        self.flip(back)
+    }
+}
+
+impl Drop for KmsDisplay {
+    fn drop(&mut self) {
+        let _ = self.wait_for_flip();
+        if let Some(mut saved) = self.saved.take() {
+            let mut connector_id = self.connector_id;
+            saved.set_connectors_ptr = &mut connector_id as *mut u32 as u64;
+            saved.count_connectors = 1;
+            if saved.fb_id != 0 {
+                let _ = ioctl(&self.device, IOCTL_MODE_SETCRTC, &mut saved);
+            }
+        }
+        for buffer in self.buffers.iter_mut() {
+            buffer.destroy(&self.device);
+        }
+    }
+}
+
+fn pick_connector(device: &File, ids: &[u32], wanted: Option<&str>) -> io::Result<Connector> {
+    let mut names = Vec::new();
+    for &id in ids {
+        let connector = get_connector(device, id)?;
+        let matches = match wanted {
+            Some(wanted) => connector.name == wanted || wanted.parse() == Ok(id),
+            None => connector.connected && !connector.modes.is_empty(),
+        };
+        if matches {
+            if !connector.connected {
+                return Err(not_found(format!("nothing is plugged into {}", connector.name)));
+            }
+            return Ok(connector);
+        }
+        names.push(format!("{} ({})", connector.name,
+                           if connector.connected { "connected" } else { "disconnected" }));
+    }
+    Err(not_found(match wanted {
+        Some(wanted) => format!("no connector `{}`; there are: {}", wanted, names.join(", ")),
+        None => format!("no connected display; connectors: {}", names.join(", ")),
+    }))
+}
+
+fn pick_mode(connector: &Connector, wanted: Option<ModeRequest>) -> io::Result<ModeInfo> {
+    let mode = match wanted {
+        Some(wanted) => connector.modes.iter().find(|mode| wanted.matches(mode)),
+        None => connector.modes.iter().find(|mode| mode.mode_type & MODE_TYPE_PREFERRED != 0)
+                                      .or_else(|| connector.modes.first()),
+    };
+    mode.copied().ok_or_else(|| {
+        let modes: Vec<String> = connector.modes.iter()
+            .map(|mode| format!("{}x{}@{}", mode.hdisplay, mode.vdisplay, mode.vrefresh)).collect();
+        not_found(format!("{} has no such mode; it offers {}", connector.name, modes.join(", ")))
+    })
+}
+
+/// The CRTC already driving the connector if there is one, otherwise the
+/// first one any of its encoders can use.
+fn pick_crtc(device: &File, connector: &Connector, crtcs: &[u32]) -> io::Result<u32> {
+    let mut encoders = connector.encoders.clone();
+    if connector.encoder_id != 0 {
+        encoders.insert(0, connector.encoder_id);
+    }
+    for encoder_id in encoders {
+        let mut encoder = GetEncoder { encoder_id, ..Default::default() };
+        if ioctl(device, IOCTL_MODE_GETENCODER, &mut encoder).is_err() {
+            continue;
+        }
+        if encoder_id == connector.encoder_id && encoder.crtc_id != 0 {
+            return Ok(encoder.crtc_id);
+        }
+        if let Some(index) = (0..crtcs.len()).find(|&index| encoder.possible_crtcs & (1 << index) != 0) {
+            return Ok(crtcs[index]);
+        }
+    }
+    Err(not_found(format!("no CRTC can drive {}", connector.name)))
+}
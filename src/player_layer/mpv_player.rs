@@ -0,0 +1,169 @@
+//! # mpv Video Output for CarPlay Client
+//!
+//! Shows the phone's video with libmpv. Frames are written into a pipe that
+//! mpv plays as `fd://`, a bare H.264 stream without any timing, so mpv is
+//! set up to show each frame the moment it is decoded instead of buffering
+//! and pacing them. The handle isn't `Send`; create the player on the
+//! thread that feeds it.
+//!
+//! The pipe is written without blocking. While it is full, mpv's events
+//! are handled and the write is retried, so a player that shut down or
+//! stopped reading is noticed rather than waited on forever.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::time::{Duration, Instant};
+
+extern crate libc;
+extern crate mpv;
+
+use mpv::{Event, MpvHandler, MpvHandlerBuilder};
+
+use super::PlayerConfig;
+use super::video_queue::Frame;
+use super::video_sink::{PlayerError, VideoSink};
+
+/// How long to wait for room in the pipe before looking at mpv's events
+/// again.
+const PIPE_POLL_MS: i32 = 20;
+/// mpv not taking any video for this long has stopped playing.
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct MpvPlayer {
+    // Dropped in this order: closing the pipe ends playback, which lets mpv
+    // shut down, and only then is the end it reads from closed
+    pipe: File,
+    mpv: MpvHandler,
+    _pipe_read: File,
+}
+
+impl MpvPlayer {
+    pub fn new(config: &PlayerConfig) -> Result<Self, PlayerError> {
+        let display = &config.display;
+        let (pipe_read, pipe) = pipe()?;
+        let mut builder = MpvHandlerBuilder::new()?;
+        builder.set_option("demuxer-lavf-format", "h264")?;
+        // Keep nothing around: no cache, no probing beyond the first frame,
+        // no pacing
+        builder.set_option("cache", "no")?;
+        builder.set_option("untimed", true)?;
+        builder.set_option("demuxer-lavf-o", "fflags=+nobuffer")?;
+        builder.set_option("demuxer-lavf-probe-info", "nostreams")?;
+        builder.set_option("demuxer-lavf-analyzeduration", 0.1)?;
+        builder.set_option("vd-lavc-threads", 1i64)?;
+        builder.set_option("aid", "no")?;
+        builder.set_option("fullscreen", display.fullscreen)?;
+        builder.set_option("geometry", format!("{}x{}", display.width, display.height).as_str())?;
+        // Touch goes to the phone, not to mpv
+        builder.set_option("osc", false)?;
+        builder.set_option("osd-level", 0i64)?;
+        builder.set_option("input-default-bindings", false)?;
+        builder.set_option("input-vo-keyboard", false)?;
+        builder.set_option("cursor-autohide", "always")?;
+        // Not every mpv version knows these; they only make things better
+        let fps = display.fps as f64;
+        if builder.set_option("container-fps-override", fps).is_err() {
+            let _ = builder.set_option("fps", fps);
+        }
+        let _ = builder.set_option("video-latency-hacks", true);
+        if let Some(vo) = &config.mpv_vo {
+            builder.set_option("vo", vo.as_str())?;
+            if vo == "drm" {
+                set_drm_options(&mut builder, config);
+            }
+        }
+        if builder.set_option("hwdec", "auto-safe").is_err() {
+            let _ = builder.try_hardware_decoding();
+        }
+
+        let mut mpv = builder.build()?;
+        mpv.command(&["loadfile", &format!("fd://{}", pipe_read.as_raw_fd())])?;
+        Ok(Self { pipe, mpv, _pipe_read: pipe_read })
+    }
+
+    /// Writes all of `data`, handling mpv's events while the pipe is full.
+    fn write_all(&mut self, mut data: &[u8]) -> Result<(), PlayerError> {
+        let mut stalled_since = Instant::now();
+        while !data.is_empty() {
+            match self.pipe.write(data) {
+                Ok(len) => {
+                    data = &data[len..];
+                    stalled_since = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.handle_events()?;
+                    if stalled_since.elapsed() >= STALL_TIMEOUT {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "mpv stopped taking video").into());
+                    }
+                    let mut pollfd = libc::pollfd { fd: self.pipe.as_raw_fd(), events: libc::POLLOUT, revents: 0 };
+                    unsafe { libc::poll(&mut pollfd, 1, PIPE_POLL_MS) };
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_events(&mut self) -> Result<(), PlayerError> {
+        // mpv queues events until they are taken, whether anyone cares or not
+        while let Some(event) = self.mpv.wait_event(0.0) {
+            match event {
+                Event::Shutdown => return Err(PlayerError::Closed),
+                Event::EndFile(Err(e)) => return Err(PlayerError::Mpv(e)),
+                Event::EndFile(Ok(_)) => return Err(PlayerError::Closed),
+                Event::VideoReconfig => println!("mpv: video reconfigured"),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VideoSink for MpvPlayer {
+    /// Waits while mpv is behind, which is what lets the video queue drop
+    /// frames instead.
+    fn show(&mut self, frame: &Frame) -> Result<(), PlayerError> {
+        self.write_all(&frame.data)?;
+        self.handle_events()
+    }
+}
+
+/// Points mpv's `drm` output at the configured connector and mode. Older
+/// mpv versions only take a mode index, so a mode they refuse is left to
+/// mpv to pick.
+fn set_drm_options(builder: &mut MpvHandlerBuilder, config: &PlayerConfig) {
+    let drm = &config.drm;
+    let mut options = vec![("drm-device", drm.device().display().to_string())];
+    if let Some(connector) = &drm.connector {
+        options.push(("drm-connector", connector.clone()));
+    }
+    if let Some(mode) = drm.mode {
+        let refresh = mode.refresh.map(|hz| format!("@{}", hz)).unwrap_or_default();
+        options.push(("drm-mode", format!("{}x{}{}", mode.width, mode.height, refresh)));
+    }
+    for (name, value) in options {
+        if let Err(e) = builder.set_option(name, value.as_str()) {
+            println!("mpv: could not set {} to {}: {}", name, value, e);
+        }
+    }
+    if drm.crtc.is_some() {
+        println!("mpv picks its own CRTC; --drm-crtc only applies to the kms sink");
+    }
+}
+
+/// Returns the read and the write end of a new pipe; only the write end
+/// doesn't block, mpv reads the other one as usual.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    let flags = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_GETFL) };
+    if (flags < 0) || (unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0) {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((read, write))
+}
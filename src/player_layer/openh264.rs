@@ -0,0 +1,140 @@
+//! # Software H.264 Decoding for CarPlay Client
+//!
+//! Decodes the phone's video on the CPU with Cisco's libopenh264, for
+//! machines without a GPU or display. Only built with the `openh264`
+//! feature, which links the system library.
+
+use std::os::raw::{c_char, c_int, c_long, c_uchar, c_uint, c_void};
+use std::ptr;
+
+use super::frame_output::YuvFrame;
+use super::video_sink::PlayerError;
+
+/// `ISVCDecoder` from `codec_api.h`: a pointer to a table of methods.
+type Decoder = *const DecoderVtbl;
+
+#[repr(C)]
+struct DecoderVtbl {
+    initialize: unsafe extern "C" fn(*mut Decoder, *const DecodingParam) -> c_long,
+    uninitialize: unsafe extern "C" fn(*mut Decoder) -> c_long,
+    decode_frame: *const c_void,
+    decode_frame_no_delay: unsafe extern "C" fn(*mut Decoder, *const c_uchar, c_int,
+                                                *mut *mut c_uchar, *mut BufferInfo) -> c_int,
+    decode_frame2: *const c_void,
+    flush_frame: *const c_void,
+    decode_parser: *const c_void,
+    decode_frame_ex: *const c_void,
+    set_option: *const c_void,
+    get_option: *const c_void,
+}
+
+#[repr(C)]
+struct VideoProperty {
+    size: c_uint,
+    bitstream_type: c_int,
+}
+
+#[repr(C)]
+struct DecodingParam {
+    file_name_restructed: *mut c_char,
+    cpu_load: c_uint,
+    target_dq_layer: c_uchar,
+    error_concealment: c_int,
+    parse_only: bool,
+    video_property: VideoProperty,
+}
+
+#[repr(C)]
+struct SysMemBuffer {
+    width: c_int,
+    height: c_int,
+    format: c_int,
+    stride: [c_int; 2],
+}
+
+#[repr(C)]
+struct BufferInfo {
+    buffer_status: c_int,
+    in_bs_time_stamp: u64,
+    out_yuv_time_stamp: u64,
+    system_buffer: SysMemBuffer,
+    dst: [*mut c_uchar; 3],
+}
+
+const VIDEO_BITSTREAM_AVC: c_int = 0;
+/// Broken pictures are left out rather than patched up.
+const ERROR_CON_DISABLE: c_int = 0;
+
+#[link(name = "openh264")]
+extern "C" {
+    fn WelsCreateDecoder(decoder: *mut *mut Decoder) -> c_long;
+    fn WelsDestroyDecoder(decoder: *mut Decoder);
+}
+
+pub struct OpenH264Decoder {
+    decoder: *mut Decoder,
+}
+
+impl OpenH264Decoder {
+    pub fn new() -> Result<Self, PlayerError> {
+        let mut decoder = ptr::null_mut();
+        if unsafe { WelsCreateDecoder(&mut decoder) } != 0 || decoder.is_null() {
+            return Err(PlayerError::Decoder("could not create an openh264 decoder".into()));
+        }
+        let param = DecodingParam {
+            file_name_restructed: ptr::null_mut(),
+            cpu_load: 0,
+            target_dq_layer: c_uchar::MAX,
+            error_concealment: ERROR_CON_DISABLE,
+            parse_only: false,
+            video_property: VideoProperty { size: 0, bitstream_type: VIDEO_BITSTREAM_AVC },
+        };
+        if unsafe { ((**decoder).initialize)(decoder, &param) } != 0 {
+            unsafe { WelsDestroyDecoder(decoder) };
+            return Err(PlayerError::Decoder("could not initialise the openh264 decoder".into()));
+        }
+        Ok(Self { decoder })
+    }
+
+    /// Decodes one access unit. Returns `None` if it produced no picture,
+    /// e.g. for parameter sets, or when references were missing.
+    pub fn decode(&mut self, data: &[u8]) -> Option<YuvFrame> {
+        let mut planes = [ptr::null_mut(); 3];
+        let mut info: BufferInfo = unsafe { std::mem::zeroed() };
+        let state = unsafe {
+            ((**self.decoder).decode_frame_no_delay)(self.decoder, data.as_ptr(), data.len() as c_int,
+                                                     planes.as_mut_ptr(), &mut info)
+        };
+        if state != 0 || info.buffer_status != 1 {
+            return None;
+        }
+        let buffer = &info.system_buffer;
+        let (width, height) = (buffer.width as usize, buffer.height as usize);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        // The decoder owns the planes until the next call; copy them out
+        let copy = |plane: *mut c_uchar, stride: c_int, width: usize, height: usize| {
+            let mut packed = Vec::with_capacity(width * height);
+            for row in 0..height {
+                let line = unsafe { std::slice::from_raw_parts(plane.add(row * stride as usize), width) };
+                packed.extend_from_slice(line);
+            }
+            packed
+        };
+        Some(YuvFrame {
+            width,
+            height,
+            y: copy(info.dst[0], buffer.stride[0], width, height),
+            u: copy(info.dst[1], buffer.stride[1], chroma_width, chroma_height),
+            v: copy(info.dst[2], buffer.stride[1], chroma_width, chroma_height),
+        })
+    }
+}
+
+impl Drop for OpenH264Decoder {
+    fn drop(&mut self) {
+        unsafe {
+            ((**self.decoder).uninitialize)(self.decoder);
+            WelsDestroyDecoder(self.decoder);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! # Playback Tap for CarPlay Client
+//!
+//! Keeps the last second of what the speakers were given, mixed down to
+//! mono, so the microphone's echo canceller can tell the car's own audio
+//! apart from the driver. Samples are numbered from the start of playback;
+//! a reader keeps its own position and so stays in step with the output
+//! however the two threads happen to be scheduled.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// How much playback is kept, in seconds.
+const CAPACITY_SECS: usize = 1;
+
+#[derive(Default)]
+struct TapBuffer {
+    sample_rate: u32,
+    /// Samples written since playback started.
+    written: u64,
+    samples: VecDeque<i16>,
+}
+
+/// A shared handle; clones see the same audio.
+#[derive(Clone, Default)]
+pub struct PlaybackTap {
+    buffer: Arc<Mutex<TapBuffer>>,
+}
+
+impl fmt::Debug for PlaybackTap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let buffer = self.buffer.lock().unwrap();
+        write!(f, "PlaybackTap({} Hz, {} samples)", buffer.sample_rate, buffer.written)
+    }
+}
+
+impl PlaybackTap {
+    /// Takes interleaved samples as they go to the output.
+    pub fn write(&self, samples: &[i16], sample_rate: u32, channels: u16) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.sample_rate != sample_rate {
+            buffer.sample_rate = sample_rate;
+            buffer.samples.clear();
+        }
+        let channels = channels.max(1) as usize;
+        let frames = samples.chunks_exact(channels)
+                            .map(|frame| (frame.iter().map(|&s| s as i32).sum::<i32>() / channels as i32) as i16);
+        let before = buffer.samples.len();
+        buffer.samples.extend(frames);
+        buffer.written += (buffer.samples.len() - before) as u64;
+        let excess = buffer.samples.len().saturating_sub(sample_rate as usize * CAPACITY_SECS);
+        buffer.samples.drain(..excess);
+    }
+
+    /// Rate of the samples kept; 0 until anything was played.
+    pub fn sample_rate(&self) -> u32 {
+        self.buffer.lock().unwrap().sample_rate
+    }
+
+    /// Number of the next sample to be written.
+    pub fn position(&self) -> u64 {
+        self.buffer.lock().unwrap().written
+    }
+
+    /// Fills `out` with the samples numbered from `from` on. Ones that have
+    /// not been played yet, or were played too long ago, are silence.
+    pub fn read(&self, from: u64, out: &mut [i16]) {
+        let buffer = self.buffer.lock().unwrap();
+        let oldest = buffer.written - buffer.samples.len() as u64;
+        for (number, sample) in (from..).zip(out.iter_mut()) {
+            *sample = match number.checked_sub(oldest) {
+                Some(index) if number < buffer.written => buffer.samples[index as usize],
+                _ => 0,
+            };
+        }
+    }
+}
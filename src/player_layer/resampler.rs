@@ -0,0 +1,134 @@
+//! # Resampling for CarPlay Client
+//!
+//! Converts 16 bit audio between sample rates and channel counts. Linear
+//! interpolation is plenty for the speech and compressed music the phones
+//! send, and cheap enough to run per packet on a head unit.
+
+/// Carries its position across calls, so a stream can be fed in blocks of
+/// any size without clicks at the joins.
+pub struct Resampler {
+    in_rate: u32,
+    out_rate: u32,
+    in_channels: usize,
+    out_channels: usize,
+    /// Where the next output frame falls, in input frames counted from
+    /// `last`.
+    position: f64,
+    /// The final input frame of the previous block.
+    last: Vec<f32>,
+    /// Reused for each output frame before it is remapped.
+    mixed: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(in_rate: u32, in_channels: u16, out_rate: u32, out_channels: u16) -> Self {
+        Self {
+            in_rate,
+            out_rate,
+            in_channels: in_channels.max(1) as usize,
+            out_channels: out_channels.max(1) as usize,
+            position: 1.0,
+            last: vec![0.0; in_channels.max(1) as usize],
+            mixed: vec![0.0; in_channels.max(1) as usize],
+        }
+    }
+
+    pub fn input_format(&self) -> (u32, u16) {
+        (self.in_rate, self.in_channels as u16)
+    }
+
+    /// Output frames per input frame, before any adjustment.
+    pub fn ratio(&self) -> f64 {
+        self.out_rate as f64 / self.in_rate as f64
+    }
+
+    /// Converts interleaved `input` and appends the result to `output`.
+    pub fn process(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        self.process_with_ratio(input, self.ratio(), output);
+    }
+
+    /// Like `process`, with the ratio of output to input frames nudged
+    /// away from the nominal one to make up for clock drift.
+    pub fn process_with_ratio(&mut self, input: &[i16], ratio: f64, output: &mut Vec<i16>) {
+        let channels = self.in_channels;
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return;
+        }
+        let step = 1.0 / ratio;
+        let sample = |last: &[f32], frame: usize, channel: usize| match frame {
+            0 => last[channel],
+            _ => input[(frame - 1) * channels + channel] as f32,
+        };
+        output.reserve(((frames as f64 * ratio) as usize + 1) * self.out_channels);
+        while self.position < frames as f64 {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+            for (channel, value) in self.mixed.iter_mut().enumerate() {
+                let a = sample(&self.last, index, channel);
+                let b = sample(&self.last, index + 1, channel);
+                *value = a + (b - a) * fraction;
+            }
+            self.remap(&self.mixed, output);
+            self.position += step;
+        }
+        self.position -= frames as f64;
+        for (channel, last) in self.last.iter_mut().enumerate() {
+            *last = input[(frames - 1) * channels + channel] as f32;
+        }
+    }
+
+    /// Mono is copied to every output channel, and anything folded down to
+    /// mono is averaged.
+    fn remap(&self, frame: &[f32], output: &mut Vec<i16>) {
+        let to_i16 = |value: f32| value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        if self.out_channels == 1 && self.in_channels > 1 {
+            output.push(to_i16(frame.iter().sum::<f32>() / frame.len() as f32));
+            return;
+        }
+        for channel in 0..self.out_channels {
+            output.push(to_i16(frame[channel % self.in_channels]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output comes a frame behind the input, so after `blocks` the final
+    /// input frame is still to come.
+    fn run(resampler: &mut Resampler, blocks: &[&[i16]]) -> Vec<i16> {
+        let mut output = Vec::new();
+        for block in blocks {
+            resampler.process(block, &mut output);
+        }
+        output
+    }
+
+    #[test]
+    fn same_format_passes_through() {
+        let mut resampler = Resampler::new(48000, 2, 48000, 2);
+        let input: Vec<i16> = (0..200).map(|n| n * 97 - 9000).collect();
+        let output = run(&mut resampler, &[&input[..120], &input[120..]]);
+        assert_eq!(output, input[..198]);
+    }
+
+    #[test]
+    fn upsampling_doubles() {
+        let mut resampler = Resampler::new(24000, 1, 48000, 1);
+        let first = run(&mut resampler, &[&[0, 100, 200, 300]]);
+        assert_eq!(first, [0, 50, 100, 150, 200, 250]);
+        let second = run(&mut resampler, &[&[400; 50]]);
+        assert_eq!(second.len(), 100);
+        assert_eq!(second[..3], [300, 350, 400]);
+    }
+
+    #[test]
+    fn channels_remap() {
+        let mut mono_to_stereo = Resampler::new(48000, 1, 48000, 2);
+        assert_eq!(run(&mut mono_to_stereo, &[&[1, 2, 3], &[4]]), [1, 1, 2, 2, 3, 3]);
+        let mut stereo_to_mono = Resampler::new(48000, 2, 48000, 1);
+        assert_eq!(run(&mut stereo_to_mono, &[&[100, 200, -50, 50], &[0, 0]]), [150, 0]);
+    }
+}
@@ -0,0 +1,192 @@
+//! # Video Queue for CarPlay Client
+//!
+//! Bounded hand-off between the link layer and the video decoder. When the
+//! decoder falls behind, frames nothing else refers to are dropped first;
+//! if that isn't enough, everything is dropped up to the next IDR frame. In
+//! a car a late picture is worse than a missing one.
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+use crate::h264::{self, FrameKind};
+use crate::link_layer::box_protocol::Video;
+use crate::link_layer::buffer_pool::Payload;
+
+/// A few frames at 60 fps; more would only add latency.
+pub const DEFAULT_CAPACITY: usize = 8;
+
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// One access unit of H.264, start codes included.
+    pub data: Payload,
+    pub kind: FrameKind,
+}
+
+impl Frame {
+    pub fn from_video(video: &Video) -> Self {
+        let data = video.payload();
+        Self { width: video.width(), height: video.height(), kind: h264::classify(&data), data }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DropStats {
+    /// Non-reference frames dropped to make room.
+    pub non_reference: u64,
+    /// Frames dropped while skipping ahead to an IDR frame.
+    pub skipped: u64,
+}
+
+struct State {
+    frames: VecDeque<Frame>,
+    /// Set after a flush; nothing but an IDR frame can be decoded now.
+    awaiting_idr: bool,
+    closed: bool,
+    stats: DropStats,
+}
+
+pub struct VideoQueue {
+    capacity: usize,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+impl VideoQueue {
+    pub fn new(capacity: usize) -> Self {
+        let state = State {
+            frames: VecDeque::with_capacity(capacity),
+            awaiting_idr: false,
+            closed: false,
+            stats: DropStats::default(),
+        };
+        Self { capacity: capacity.max(1), state: Mutex::new(state), ready: Condvar::new() }
+    }
+
+    /// Queues a frame, dropping whatever has to go to stay within capacity.
+    /// Returns `true` while the queue is waiting for an IDR frame, i.e. when
+    /// asking the phone for one would help.
+    pub fn push(&self, frame: Frame) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.awaiting_idr {
+            if frame.kind != FrameKind::Idr {
+                state.stats.skipped += 1;
+                return true;
+            }
+            state.awaiting_idr = false;
+        }
+        if state.frames.len() >= self.capacity {
+            if frame.kind == FrameKind::NonReference {
+                state.stats.non_reference += 1;
+                return false;
+            }
+            match state.frames.iter().position(|queued| queued.kind == FrameKind::NonReference) {
+                Some(index) => {
+                    state.frames.remove(index);
+                    state.stats.non_reference += 1;
+                }
+                None => {
+                    // Every queued frame is needed by the next; all of them
+                    // go, and the decoder restarts from an IDR frame
+                    state.stats.skipped += state.frames.len() as u64;
+                    state.frames.clear();
+                    if frame.kind != FrameKind::Idr {
+                        state.stats.skipped += 1;
+                        state.awaiting_idr = true;
+                        return true;
+                    }
+                }
+            }
+        }
+        state.frames.push_back(frame);
+        self.ready.notify_one();
+        false
+    }
+
+    /// Waits for the next frame; `None` once the queue is closed and empty.
+    pub fn pop(&self) -> Option<Frame> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Wakes the consumer; frames already queued can still be popped.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    pub fn stats(&self) -> DropStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: FrameKind, tag: u8) -> Frame {
+        Frame { width: 800, height: 480, data: Payload::from(vec![tag]), kind }
+    }
+
+    /// Everything queued, by tag.
+    fn drain(queue: &VideoQueue) -> Vec<u8> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop()).map(|frame| frame.data[0]).collect()
+    }
+
+    #[test]
+    fn non_reference_frames_go_first() {
+        let queue = VideoQueue::new(3);
+        assert!(!queue.push(frame(FrameKind::Idr, 0)));
+        assert!(!queue.push(frame(FrameKind::NonReference, 1)));
+        assert!(!queue.push(frame(FrameKind::Reference, 2)));
+        assert!(!queue.push(frame(FrameKind::Reference, 3)));
+        assert_eq!(queue.stats(), DropStats { non_reference: 1, skipped: 0 });
+        assert_eq!(drain(&queue), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn queue_full_of_non_reference_frames() {
+        let queue = VideoQueue::new(3);
+        for tag in 0..3 {
+            assert!(!queue.push(frame(FrameKind::NonReference, tag)));
+        }
+        // A new non-reference frame is the one to go
+        assert!(!queue.push(frame(FrameKind::NonReference, 3)));
+        // A reference frame takes the oldest one's place
+        assert!(!queue.push(frame(FrameKind::Reference, 4)));
+        assert_eq!(queue.stats(), DropStats { non_reference: 2, skipped: 0 });
+        assert_eq!(drain(&queue), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn reference_frames_drop_to_the_next_idr() {
+        let queue = VideoQueue::new(2);
+        assert!(!queue.push(frame(FrameKind::Idr, 0)));
+        assert!(!queue.push(frame(FrameKind::Reference, 1)));
+        assert!(queue.push(frame(FrameKind::Reference, 2)));
+        assert!(queue.push(frame(FrameKind::NonReference, 3)));
+        assert!(!queue.push(frame(FrameKind::Idr, 4)));
+        assert!(!queue.push(frame(FrameKind::Reference, 5)));
+        assert_eq!(queue.stats(), DropStats { non_reference: 0, skipped: 4 });
+        assert_eq!(drain(&queue), vec![4, 5]);
+    }
+
+    #[test]
+    fn idr_frame_replaces_a_full_queue() {
+        let queue = VideoQueue::new(2);
+        assert!(!queue.push(frame(FrameKind::Idr, 0)));
+        assert!(!queue.push(frame(FrameKind::Reference, 1)));
+        assert!(!queue.push(frame(FrameKind::Idr, 2)));
+        assert_eq!(queue.stats(), DropStats { non_reference: 0, skipped: 2 });
+        assert_eq!(drain(&queue), vec![2]);
+    }
+}
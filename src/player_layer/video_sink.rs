@@ -0,0 +1,243 @@
+//! # Video Sinks for CarPlay Client
+//!
+//! Where decoded-to-be frames end up. `mpv` is the one that matters in a
+//! car; `file` writes the stream out as a raw `.h264` for inspecting with
+//! other tools, and `null` shows nothing but checks that what arrives could
+//! be decoded, for running on machines without a display. With the
+//! `openh264` feature, `y4m` and `png` decode in software and write out the
+//! pictures themselves, and `kms` puts them on a screen with no compositor.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+extern crate mpv;
+
+use crate::h264;
+use super::PlayerConfig;
+use super::mpv_player::MpvPlayer;
+use super::video_queue::Frame;
+#[cfg(feature = "openh264")]
+use super::frame_output::{FrameOutput, PngSnapshots, Y4mWriter};
+#[cfg(feature = "openh264")]
+use super::kms::KmsDisplay;
+#[cfg(feature = "openh264")]
+use super::openh264::OpenH264Decoder;
+
+#[derive(Debug)]
+pub enum PlayerError {
+    Mpv(mpv::Error),
+    Io(io::Error),
+    /// The mpv window was closed or mpv shut down on its own.
+    Closed,
+    /// The software decoder could not be set up.
+    Decoder(String),
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlayerError::Mpv(e) => write!(f, "mpv error: {}", e),
+            PlayerError::Io(e) => write!(f, "could not write video: {}", e),
+            PlayerError::Closed => write!(f, "mpv shut down"),
+            PlayerError::Decoder(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PlayerError {}
+
+impl From<mpv::Error> for PlayerError {
+    fn from(e: mpv::Error) -> Self {
+        PlayerError::Mpv(e)
+    }
+}
+
+impl From<io::Error> for PlayerError {
+    fn from(e: io::Error) -> Self {
+        PlayerError::Io(e)
+    }
+}
+
+/// Something that takes H.264 access units, in stream order.
+pub trait VideoSink {
+    /// An error means the sink is done for good.
+    fn show(&mut self, frame: &Frame) -> Result<(), PlayerError>;
+    /// What the sink found out about the stream, if it looks.
+    fn stream_stats(&self) -> Option<StreamStats> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SinkKind {
+    #[default]
+    Mpv,
+    File(PathBuf),
+    Null,
+    /// Decoded pictures as a Y4M stream.
+    Y4m(PathBuf),
+    /// Decoded pictures as PNG snapshots in a directory.
+    Png(PathBuf),
+    /// Decoded pictures straight to a DRM/KMS output.
+    Kms,
+}
+
+impl SinkKind {
+    /// `mpv`, `null`, `kms`, `file:PATH`, `y4m:PATH` or `png:DIR`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (kind, sink) = match value {
+            "mpv" => return Ok(SinkKind::Mpv),
+            "null" => return Ok(SinkKind::Null),
+            "kms" => ("kms", SinkKind::Kms),
+            _ => {
+                let (kind, path) = value.split_once(':').filter(|(_, path)| !path.is_empty())
+                    .ok_or(format!("unknown video sink `{}`, expected mpv, null, kms, file:PATH, y4m:PATH or png:DIR", value))?;
+                match kind {
+                    "file" => return Ok(SinkKind::File(path.into())),
+                    "y4m" => (kind, SinkKind::Y4m(path.into())),
+                    "png" => (kind, SinkKind::Png(path.into())),
+                    _ => return Err(format!("unknown video sink `{}`", kind)),
+                }
+            }
+        };
+        if cfg!(feature = "openh264") {
+            Ok(sink)
+        } else {
+            Err(format!("the {} video sink needs a build with the openh264 feature", kind))
+        }
+    }
+
+    /// Sinks may not be `Send`; open them on the thread that feeds them.
+    pub fn open(&self, config: &PlayerConfig) -> Result<Box<dyn VideoSink>, PlayerError> {
+        Ok(match self {
+            SinkKind::Mpv => Box::new(MpvPlayer::new(config)?),
+            SinkKind::File(path) => Box::new(FileSink::create(path)?),
+            SinkKind::Null => Box::new(NullSink::default()),
+            #[cfg(feature = "openh264")]
+            SinkKind::Y4m(path) => Box::new(DecodingSink::new(Y4mWriter::create(path, config.display.fps)?)?),
+            #[cfg(feature = "openh264")]
+            SinkKind::Png(dir) => Box::new(DecodingSink::new(PngSnapshots::create(dir, config.snapshot_interval)?)?),
+            #[cfg(feature = "openh264")]
+            SinkKind::Kms => Box::new(DecodingSink::new(KmsDisplay::open(&config.drm)?)?),
+            #[cfg(not(feature = "openh264"))]
+            SinkKind::Y4m(_) | SinkKind::Png(_) | SinkKind::Kms => {
+                return Err(PlayerError::Decoder("built without the openh264 feature".into()));
+            }
+        })
+    }
+}
+
+/// Writes the stream out as it arrives; the file plays with anything that
+/// takes a raw H.264 elementary stream.
+pub struct FileSink {
+    out: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        println!("Writing video to {}", path.display());
+        Ok(Self { out: BufWriter::new(File::create(path)?) })
+    }
+}
+
+impl VideoSink for FileSink {
+    fn show(&mut self, frame: &Frame) -> Result<(), PlayerError> {
+        self.out.write_all(&frame.data)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    pub frames: u64,
+    pub idr_frames: u64,
+    /// Frames a decoder could have shown: well formed, with parameter sets
+    /// and an IDR frame before them and nothing broken in between.
+    pub decodable: u64,
+    /// Frames that aren't Annex B NAL units at all.
+    pub malformed: u64,
+}
+
+impl fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} frames, {} decodable, {} IDR, {} malformed",
+               self.frames, self.decodable, self.idr_frames, self.malformed)
+    }
+}
+
+/// Shows nothing, but follows the stream the way a decoder would.
+#[derive(Default)]
+pub struct NullSink {
+    stats: StreamStats,
+    have_sps: bool,
+    have_pps: bool,
+    /// Set from an IDR frame until something breaks the chain of references.
+    decoding: bool,
+}
+
+impl VideoSink for NullSink {
+    fn show(&mut self, frame: &Frame) -> Result<(), PlayerError> {
+        self.stats.frames += 1;
+        let data = &frame.data[..];
+        let nals = h264::nal_units(data);
+        let well_formed = !nals.is_empty() &&
+            h264::start_codes(data).first().map(|&(offset, _)| offset) == Some(0) &&
+            nals.iter().all(|nal| nal[0] & 0x80 == 0 && h264::nal_type(nal) != 0);
+        if !well_formed {
+            self.stats.malformed += 1;
+            self.decoding = false;
+            return Ok(());
+        }
+
+        let mut has_slice = false;
+        for nal in &nals {
+            match h264::nal_type(nal) {
+                h264::NAL_SPS => self.have_sps = true,
+                h264::NAL_PPS => self.have_pps = true,
+                h264::NAL_IDR_SLICE => {
+                    has_slice = true;
+                    self.decoding = self.have_sps && self.have_pps;
+                }
+                h264::NAL_SLICE => has_slice = true,
+                _ => {},
+            }
+        }
+        if frame.kind == h264::FrameKind::Idr {
+            self.stats.idr_frames += 1;
+        }
+        if has_slice && self.decoding {
+            self.stats.decodable += 1;
+        }
+        Ok(())
+    }
+
+    fn stream_stats(&self) -> Option<StreamStats> {
+        Some(self.stats)
+    }
+}
+
+/// Decodes in software and hands the pictures to a `FrameOutput`.
+#[cfg(feature = "openh264")]
+pub struct DecodingSink<O: FrameOutput> {
+    decoder: OpenH264Decoder,
+    output: O,
+}
+
+#[cfg(feature = "openh264")]
+impl<O: FrameOutput> DecodingSink<O> {
+    pub fn new(output: O) -> Result<Self, PlayerError> {
+        Ok(Self { decoder: OpenH264Decoder::new()?, output })
+    }
+}
+
+#[cfg(feature = "openh264")]
+impl<O: FrameOutput> VideoSink for DecodingSink<O> {
+    fn show(&mut self, frame: &Frame) -> Result<(), PlayerError> {
+        if let Some(picture) = self.decoder.decode(&frame.data) {
+            self.output.write(&picture)?;
+        }
+        Ok(())
+    }
+}
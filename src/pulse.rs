@@ -0,0 +1,106 @@
+//! # PulseAudio for CarPlay Client
+//!
+//! Plays interleaved 16 bit audio through PulseAudio's simple API, which
+//! also covers PipeWire's Pulse server. Only built with the `pulse`
+//! feature, which links the system libraries.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::time::Duration;
+
+const PA_SAMPLE_S16LE: c_int = 3;
+const PA_STREAM_PLAYBACK: c_int = 1;
+/// Leave a buffer setting to the server.
+const DEFAULT: u32 = u32::MAX;
+
+#[repr(C)]
+struct SampleSpec {
+    format: c_int,
+    rate: u32,
+    channels: u8,
+}
+
+#[repr(C)]
+struct BufferAttr {
+    maxlength: u32,
+    tlength: u32,
+    prebuf: u32,
+    minreq: u32,
+    fragsize: u32,
+}
+
+#[link(name = "pulse-simple")]
+#[link(name = "pulse")]
+extern "C" {
+    fn pa_simple_new(server: *const c_char, name: *const c_char, dir: c_int, dev: *const c_char,
+                     stream_name: *const c_char, spec: *const SampleSpec, map: *const c_void,
+                     attr: *const BufferAttr, error: *mut c_int) -> *mut c_void;
+    fn pa_simple_write(simple: *mut c_void, data: *const c_void, bytes: usize, error: *mut c_int) -> c_int;
+    fn pa_simple_drain(simple: *mut c_void, error: *mut c_int) -> c_int;
+    fn pa_simple_free(simple: *mut c_void);
+    fn pa_strerror(error: c_int) -> *const c_char;
+}
+
+fn error(what: &str, code: c_int) -> io::Error {
+    let message = unsafe { CStr::from_ptr(pa_strerror(code)) }.to_string_lossy();
+    io::Error::other(format!("PulseAudio {}: {}", what, message))
+}
+
+/// A playback stream on the default server.
+pub struct Simple {
+    simple: *mut c_void,
+}
+
+impl Simple {
+    /// `device` is a sink name, or `None` for the default sink.
+    pub fn open_playback(device: Option<&str>, stream_name: &str, rate: u32, channels: u16,
+                         latency: Duration) -> io::Result<Self> {
+        let invalid = |_| io::Error::from(io::ErrorKind::InvalidInput);
+        let device = device.map(CString::new).transpose().map_err(invalid)?;
+        let stream_name = CString::new(stream_name).map_err(invalid)?;
+        let client_name = CString::new(env!("CARGO_PKG_NAME")).unwrap();
+        let spec = SampleSpec { format: PA_SAMPLE_S16LE, rate, channels: channels as u8 };
+        let bytes_per_second = rate as u64 * channels as u64 * 2;
+        let attr = BufferAttr {
+            maxlength: DEFAULT,
+            tlength: (bytes_per_second * latency.as_millis() as u64 / 1000) as u32,
+            prebuf: DEFAULT,
+            minreq: DEFAULT,
+            fragsize: DEFAULT,
+        };
+        let mut code = 0;
+        let simple = unsafe {
+            pa_simple_new(ptr::null(), client_name.as_ptr(), PA_STREAM_PLAYBACK,
+                          device.as_ref().map_or(ptr::null(), |device| device.as_ptr()),
+                          stream_name.as_ptr(), &spec, ptr::null(), &attr, &mut code)
+        };
+        if simple.is_null() {
+            return Err(error("could not connect", code));
+        }
+        Ok(Self { simple })
+    }
+
+    /// Blocks until the server has room for `samples`.
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut code = 0;
+        let result = unsafe {
+            pa_simple_write(self.simple, samples.as_ptr() as *const c_void, samples.len() * 2, &mut code)
+        };
+        if result < 0 {
+            return Err(error("write failed", code));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Simple {
+    fn drop(&mut self) {
+        let mut code = 0;
+        unsafe {
+            pa_simple_drain(self.simple, &mut code);
+            pa_simple_free(self.simple);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! # Signal Handling for CarPlay Client
+//!
+//! SIGINT and SIGTERM only raise a flag. Every layer checks it in its loop
+//! and winds itself down, so the dongle gets closed and handed back to the
+//! kernel instead of being left claimed. A second signal exits at once.
+//!
+//! SIGUSR1 turns traffic capture on and off.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+extern crate libc;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static CAPTURE_TOGGLE: AtomicBool = AtomicBool::new(false);
+
+/// How often `sleep` checks whether it should stop early.
+const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        // Still stuck after the first one; the shell convention for death
+        // by signal is 128 + its number
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+extern "C" fn on_capture_signal(_signal: libc::c_int) {
+    CAPTURE_TOGGLE.store(true, Ordering::SeqCst);
+}
+
+pub fn install() -> io::Result<()> {
+    let handlers = [
+        (libc::SIGINT, on_signal as extern "C" fn(libc::c_int)),
+        (libc::SIGTERM, on_signal),
+        (libc::SIGUSR1, on_capture_signal),
+    ];
+    for &(signal, handler) in &handlers {
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Asks every layer to stop, as if a signal had arrived.
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Whether SIGUSR1 arrived since the last call.
+pub fn capture_toggle_requested() -> bool {
+    CAPTURE_TOGGLE.swap(false, Ordering::SeqCst)
+}
+
+/// Sleeps for `duration`, returning early if shutdown is requested.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !shutdown_requested() {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        thread::sleep(SLEEP_STEP.min(deadline - now));
+    }
+}
@@ -0,0 +1,216 @@
+//! # WAV Files for CarPlay Client
+//!
+//! Just enough RIFF/WAVE handling for 16 bit PCM, which is all the dongle
+//! ever deals in. Used for test audio sources, the dongle emulator and the
+//! `wav` audio output.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+pub struct Wav {
+    pub format: WavFormat,
+    /// Interleaved samples.
+    pub samples: Vec<i16>,
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("not a 16 bit PCM WAV file: {}", what))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Wav> {
+    let data = fs::read(path)?;
+    if (data.len() < 12) || (&data[0..4] != b"RIFF") || (&data[8..12] != b"WAVE") {
+        return Err(invalid("missing RIFF/WAVE header"));
+    }
+    let mut format: Option<WavFormat> = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let chunk_id = &data[offset..offset + 4];
+        let chunk_len = u32_at(&data, offset + 4) as usize;
+        let body = offset + 8;
+        let end = std::cmp::min(body + chunk_len, data.len());
+        match chunk_id {
+            b"fmt " => {
+                if (chunk_len < 16) || (body + 16 > data.len()) {
+                    return Err(invalid("short fmt chunk"));
+                }
+                if (u16_at(&data, body) != 1) || (u16_at(&data, body + 14) != 16) {
+                    return Err(invalid("unsupported sample format"));
+                }
+                let channels = u16_at(&data, body + 2);
+                let sample_rate = u32_at(&data, body + 4);
+                if (channels == 0) || (sample_rate == 0) {
+                    return Err(invalid("no channels or zero sample rate"));
+                }
+                format = Some(WavFormat { channels, sample_rate });
+            }
+            b"data" => {
+                let format = format.ok_or_else(|| invalid("data before fmt chunk"))?;
+                let samples = data[body..end].chunks_exact(2)
+                                             .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+                                             .collect();
+                return Ok(Wav { format, samples });
+            }
+            _ => {},
+        }
+        // Chunks are padded to an even length
+        offset = body + chunk_len + (chunk_len & 1);
+    }
+    Err(invalid("no data chunk"))
+}
+
+/// Converts interleaved samples to the little endian bytes the dongle uses.
+pub fn to_bytes(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+}
+
+/// Writes samples out as they come. The header is written with the sizes
+/// left at zero and patched up by `finish`, or on drop.
+pub struct WavWriter {
+    out: BufWriter<File>,
+    format: WavFormat,
+    /// Capped so the RIFF size, 36 more, still fits in a u32.
+    data_len: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, format: WavFormat) -> io::Result<Self> {
+        let mut writer = Self {
+            out: BufWriter::new(File::create(path)?),
+            format,
+            data_len: 0,
+            finished: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    pub fn format(&self) -> WavFormat {
+        self.format
+    }
+
+    /// Interleaved samples.
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        self.out.write_all(&to_bytes(samples))?;
+        let bytes = (samples.len() as u64 * 2).min(u32::MAX as u64) as u32;
+        self.data_len = self.data_len.saturating_add(bytes).min(u32::MAX - 36);
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let WavFormat { sample_rate, channels } = self.format;
+        let block_align = channels * 2;
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(36 + self.data_len).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&self.data_len.to_le_bytes());
+        self.out.write_all(&header)
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_fmt_chunk() {
+        let path = std::env::temp_dir().join(format!("carplay-short-fmt-{}.wav", std::process::id()));
+        let mut data = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&[1, 0, 2, 0]);
+        fs::write(&path, &data).unwrap();
+        let result = read(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn empty_format() {
+        for &(channels, sample_rate) in &[(0u16, 16000u32), (1, 0)] {
+            let path = std::env::temp_dir().join(format!("carplay-empty-fmt-{}.wav", std::process::id()));
+            let mut data = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+            data.extend_from_slice(&16u32.to_le_bytes());
+            data.extend_from_slice(&1u16.to_le_bytes());
+            data.extend_from_slice(&channels.to_le_bytes());
+            data.extend_from_slice(&sample_rate.to_le_bytes());
+            data.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+            data.extend_from_slice(&[2, 0, 16, 0]);
+            data.extend_from_slice(b"data\x04\0\0\0\0\0\0\0");
+            fs::write(&path, &data).unwrap();
+            let result = read(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+        }
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let path = std::env::temp_dir().join(format!("carplay-round-trip-{}.wav", std::process::id()));
+        let format = WavFormat { sample_rate: 16000, channels: 2 };
+        let samples: Vec<i16> = (0..100).map(|n| n * 300 - 15000).collect();
+        let mut writer = WavWriter::create(&path, format).unwrap();
+        writer.write(&samples[..40]).unwrap();
+        writer.write(&samples[40..]).unwrap();
+        drop(writer);
+        let wav = read(&path);
+        fs::remove_file(&path).unwrap();
+        let wav = wav.unwrap();
+        assert_eq!(wav.format, format);
+        assert_eq!(wav.samples, samples);
+    }
+
+    #[test]
+    fn sizes_stop_short_of_overflowing() {
+        let path = std::env::temp_dir().join(format!("carplay-huge-{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, WavFormat { sample_rate: 8000, channels: 1 }).unwrap();
+        // As if nearly 4 GiB had been written already
+        writer.data_len = u32::MAX - 40;
+        writer.write(&[0; 8]).unwrap();
+        writer.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(u32_at(&data, 4), u32::MAX);
+        assert_eq!(u32_at(&data, 40), u32::MAX - 36);
+    }
+}
@@ -0,0 +1,77 @@
+//! Plays a small session recording through the link and player layers, as
+//! `--replay` does, and checks what the video sink made of it.
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use carplay_client::link_layer::box_protocol::*;
+use carplay_client::link_layer::capture::Direction;
+use carplay_client::link_layer::recording::Recorder;
+use carplay_client::link_layer::{LinkConfig, LinkLayer, TransportKind};
+use carplay_client::player_layer::audio_output::OutputKind;
+use carplay_client::player_layer::video_sink::SinkKind;
+use carplay_client::player_layer::{PlayerConfig, PlayerLayer};
+
+const SPS: &[u8] = &[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1f];
+const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80];
+const IDR: &[u8] = &[0, 0, 0, 1, 0x65, 0x88, 0x84];
+const P: &[u8] = &[0, 0, 0, 1, 0x41, 0x9a, 0x02];
+
+/// Fewer than the video queue holds, so none are dropped however the
+/// threads are scheduled.
+const FRAMES: u64 = 6;
+
+/// A phone being plugged in and `FRAMES` pictures, starting with an IDR
+/// frame, as the box would send them. What the client sent is in there too.
+fn write_fixture(path: &Path) {
+    let recorder = Recorder::new();
+    recorder.start(path).unwrap();
+    recorder.record(Direction::Out, &MsgType::Heartbeat(Heartbeat::new()).serialize());
+    recorder.record(Direction::In, &MsgType::DevPlug(DevPlug::new(3)).serialize());
+    for index in 0..FRAMES {
+        let data = match index {
+            0 => [SPS, PPS, IDR].concat(),
+            _ => P.to_vec(),
+        };
+        recorder.record(Direction::In, &MsgType::Video(Video::new(800, 480, data)).serialize());
+    }
+    recorder.stop();
+}
+
+#[test]
+fn replayed_video_reaches_the_sink() {
+    let path = std::env::temp_dir().join(format!("carplay-replay-{}.rec", std::process::id()));
+    write_fixture(&path);
+
+    let (player_tx, player_rx) = mpsc::channel();
+    let (link_tx, link_rx) = mpsc::channel();
+    let link_config = LinkConfig {
+        transport: TransportKind::Replay { path: path.clone(), speed: 0.0 },
+        ..Default::default()
+    };
+    let mut link = LinkLayer::new(link_config, player_tx, link_rx).unwrap();
+    // Returns once the recording has been played and the link shut down
+    let link_thread = thread::spawn(move || link.run());
+
+    let mut player_config = PlayerConfig { sink: SinkKind::Null, ..Default::default() };
+    player_config.audio.output = OutputKind::Null;
+    let mut player = PlayerLayer::new(player_config, link_tx);
+    for event in player_rx {
+        player.handle_event(event);
+    }
+    link_thread.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // The video thread works through the queue on its own
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while player.stream_stats().map_or(0, |stats| stats.frames) < FRAMES && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let stats = player.stream_stats().unwrap();
+    assert_eq!(stats.frames, FRAMES);
+    assert_eq!(stats.idr_frames, 1);
+    assert_eq!(stats.decodable, FRAMES);
+    assert_eq!(stats.malformed, 0);
+}